@@ -1,12 +1,13 @@
+use crate::core::{
+    Health, Leases, Metrics, RabbitConnection, RedisCache, Scheduler, SettingsStore, Shutdown,
+};
 use crate::model::PluginConfig;
 use deadpool_redis::Pool as RedisPool;
-use lapin::Connection;
 use std::collections::HashMap;
 use std::sync::Arc;
 #[cfg(feature = "tagscript")]
 use tagscript::Interpreter;
 use tokio::sync::RwLock;
-use twilight_cache_inmemory::InMemoryCache;
 use twilight_http::Client as HttpClient;
 use twilight_model::{
     id::{marker::UserMarker, Id},
@@ -15,8 +16,8 @@ use twilight_model::{
 
 #[derive(Clone)]
 pub struct Context {
-    pub cache: Arc<InMemoryCache>,
-    pub rabbit_conn: Arc<Connection>,
+    pub cache: Arc<RedisCache>,
+    pub rabbit_conn: Arc<RabbitConnection>,
     #[cfg(feature = "mongo")]
     pub mongo_client: Arc<mongodb::Client>,
     #[cfg(feature = "mongo")]
@@ -26,6 +27,12 @@ pub struct Context {
     pub user: CurrentUser,
     pub owners: HashMap<Id<UserMarker>, Arc<User>>,
     pub plugin_config: Arc<RwLock<PluginConfig>>,
+    pub settings: Arc<SettingsStore>,
+    pub scheduler: Arc<Scheduler>,
+    pub leases: Arc<Leases>,
+    pub shutdown: Shutdown,
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
     #[cfg(feature = "tagscript")]
     pub interpreter: Arc<Interpreter>,
 }
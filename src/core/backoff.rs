@@ -0,0 +1,91 @@
+use crate::core::Result;
+use std::future::Future;
+use tokio::time::{sleep, Duration};
+use tracing::{event, Level};
+
+/// Exponential backoff between reconnection attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// Delay before retry number `attempt` (counting from 0): `initial`,
+    /// doubled for every attempt before it, up to `max`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+
+    /// Returns the delay before the next attempt and doubles it, up to `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay(self.attempts);
+        self.attempts = self.attempts.saturating_add(1);
+        delay
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(60))
+    }
+}
+
+/// Runs `attempt` until it succeeds, sleeping with exponential backoff in
+/// between. Used for dependencies the worker can't start without.
+///
+/// Only transient errors are retried, anything else (bad credentials, ...)
+/// is returned straight away.
+pub async fn retry<T, F, Fut>(what: &str, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = Backoff::default();
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(why) if !why.is_transient() => return Err(why),
+            Err(why) => {
+                let delay = backoff.next_delay();
+                event!(
+                    Level::WARN,
+                    "Failed to {}, retrying in {:?}: {:?}",
+                    what,
+                    delay,
+                    why
+                );
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn delay_saturates_for_large_attempts() {
+        let backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(600));
+        assert_eq!(backoff.delay(0), Duration::from_secs(5));
+        assert_eq!(backoff.delay(100), Duration::from_secs(600));
+    }
+}
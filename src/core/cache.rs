@@ -0,0 +1,443 @@
+use crate::core::prelude::*;
+use deadpool_redis::redis::{pipe, Pipeline};
+use deadpool_redis::Pool as RedisPool;
+use serde::de::DeserializeOwned;
+use tokio::time::Duration;
+use tracing::warn;
+use twilight_model::channel::{Channel, Message};
+use twilight_model::gateway::payload::incoming::{MemberUpdate, MessageUpdate};
+use twilight_model::guild::{Member, Role};
+use twilight_model::user::User;
+
+/// Times a partial update is retried when another worker changes the same
+/// entity in between.
+const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+/// Deletes everything cached for a guild, using the sets indexing its
+/// channels, members and roles.
+///
+/// KEYS: channels set, members set, roles set
+/// ARGV: guild id
+const PURGE_GUILD_SCRIPT: &str = r#"
+for _, id in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    redis.call('DEL', 'cache:channel:' .. id)
+end
+for _, id in ipairs(redis.call('SMEMBERS', KEYS[2])) do
+    redis.call('DEL', 'cache:member:' .. ARGV[1] .. ':' .. id)
+end
+for _, id in ipairs(redis.call('SMEMBERS', KEYS[3])) do
+    redis.call('DEL', 'cache:role:' .. id)
+end
+redis.call('DEL', KEYS[1], KEYS[2], KEYS[3])
+"#;
+
+fn message_key(id: Id<MessageMarker>) -> String {
+    format!("cache:message:{}", id.get())
+}
+
+fn user_key(id: Id<UserMarker>) -> String {
+    format!("cache:user:{}", id.get())
+}
+
+/// Id of the last user seen with a name.
+fn user_name_key(name: &str) -> String {
+    format!("cache:user_name:{}", name)
+}
+
+fn member_key(guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> String {
+    format!("cache:member:{}:{}", guild_id.get(), user_id.get())
+}
+
+fn channel_key(id: Id<ChannelMarker>) -> String {
+    format!("cache:channel:{}", id.get())
+}
+
+fn role_key(id: Id<RoleMarker>) -> String {
+    format!("cache:role:{}", id.get())
+}
+
+fn guild_channels_key(guild_id: Id<GuildMarker>) -> String {
+    format!("cache:guild:{}:channels", guild_id.get())
+}
+
+fn guild_members_key(guild_id: Id<GuildMarker>) -> String {
+    format!("cache:guild:{}:members", guild_id.get())
+}
+
+fn guild_roles_key(guild_id: Id<GuildMarker>) -> String {
+    format!("cache:guild:{}:roles", guild_id.get())
+}
+
+/// Gateway cache shared by every worker, so lookups don't depend on which
+/// pod happened to consume the event that filled it.
+///
+/// Entities are stored as JSON under `cache:{kind}:{id}`. Messages expire
+/// after `message_ttl`, everything else `entity_ttl` after it was last seen,
+/// and a guild's channels, members and roles are indexed under
+/// `cache:guild:{id}:{kind}` so they can be dropped when the bot leaves it.
+pub struct RedisCache {
+    pool: Arc<RedisPool>,
+    message_ttl: Duration,
+    entity_ttl: Duration,
+}
+
+impl RedisCache {
+    pub fn new(pool: Arc<RedisPool>, message_ttl: Duration, entity_ttl: Duration) -> Self {
+        Self {
+            pool,
+            message_ttl,
+            entity_ttl,
+        }
+    }
+
+    /// Updates the cache with the contents of a gateway event.
+    pub async fn update(&self, event: &Event) -> Result<()> {
+        let mut pipe = pipe();
+        match event {
+            Event::MessageCreate(message) => {
+                self.cache_message(&mut pipe, message)?;
+                self.cache_user(&mut pipe, &message.author)?;
+            }
+            Event::MessageUpdate(update) => return self.update_message(update).await,
+            Event::MessageDelete(delete) => {
+                pipe.cmd("DEL").arg(message_key(delete.id));
+            }
+            Event::MessageDeleteBulk(delete) => {
+                let keys: Vec<_> = delete.ids.iter().map(|id| message_key(*id)).collect();
+                pipe.cmd("DEL").arg(keys);
+            }
+            Event::GuildCreate(guild) => {
+                for channel in guild.channels.iter().chain(guild.threads.iter()) {
+                    self.cache_channel(&mut pipe, guild.id, channel)?;
+                }
+                for member in &guild.members {
+                    self.cache_member(&mut pipe, member)?;
+                }
+                for role in &guild.roles {
+                    self.cache_role(&mut pipe, guild.id, role)?;
+                }
+            }
+            // Unavailable guilds are an outage, they come back with everything
+            // they had
+            Event::GuildDelete(delete) if !delete.unavailable => {
+                pipe.cmd("EVAL")
+                    .arg(PURGE_GUILD_SCRIPT)
+                    .arg(3)
+                    .arg(guild_channels_key(delete.id))
+                    .arg(guild_members_key(delete.id))
+                    .arg(guild_roles_key(delete.id))
+                    .arg(delete.id.get());
+            }
+            Event::MemberAdd(member) => self.cache_member(&mut pipe, member)?,
+            Event::MemberUpdate(update) => return self.update_member(update).await,
+            Event::MemberRemove(remove) => {
+                pipe.cmd("DEL")
+                    .arg(member_key(remove.guild_id, remove.user.id))
+                    .cmd("SREM")
+                    .arg(guild_members_key(remove.guild_id))
+                    .arg(remove.user.id.get());
+            }
+            Event::MemberChunk(chunk) => {
+                for member in &chunk.members {
+                    self.cache_member(&mut pipe, member)?;
+                }
+            }
+            Event::ChannelCreate(channel) => match channel.guild_id {
+                Some(guild_id) => self.cache_channel(&mut pipe, guild_id, &channel.0)?,
+                None => self.set_json(&mut pipe, channel_key(channel.id), &channel.0)?,
+            },
+            Event::ChannelUpdate(channel) => match channel.guild_id {
+                Some(guild_id) => self.cache_channel(&mut pipe, guild_id, &channel.0)?,
+                None => self.set_json(&mut pipe, channel_key(channel.id), &channel.0)?,
+            },
+            Event::ChannelDelete(channel) => {
+                pipe.cmd("DEL").arg(channel_key(channel.id));
+                if let Some(guild_id) = channel.guild_id {
+                    pipe.cmd("SREM")
+                        .arg(guild_channels_key(guild_id))
+                        .arg(channel.id.get());
+                }
+            }
+            Event::ThreadDelete(thread) => {
+                pipe.cmd("DEL")
+                    .arg(channel_key(thread.id))
+                    .cmd("SREM")
+                    .arg(guild_channels_key(thread.guild_id))
+                    .arg(thread.id.get());
+            }
+            Event::RoleCreate(create) => {
+                self.cache_role(&mut pipe, create.guild_id, &create.role)?
+            }
+            Event::RoleUpdate(update) => {
+                self.cache_role(&mut pipe, update.guild_id, &update.role)?
+            }
+            Event::RoleDelete(delete) => {
+                pipe.cmd("DEL")
+                    .arg(role_key(delete.role_id))
+                    .cmd("SREM")
+                    .arg(guild_roles_key(delete.guild_id))
+                    .arg(delete.role_id.get());
+            }
+            _ => return Ok(()),
+        }
+
+        let mut conn = self.pool.get().await?;
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    pub async fn message(&self, id: Id<MessageMarker>) -> Result<Option<Message>> {
+        self.get_json(message_key(id)).await
+    }
+
+    pub async fn user(&self, id: Id<UserMarker>) -> Result<Option<User>> {
+        self.get_json(user_key(id)).await
+    }
+
+    /// Id of the last user seen with this name.
+    pub async fn user_by_name(&self, name: &str) -> Result<Option<Id<UserMarker>>> {
+        let mut conn = self.pool.get().await?;
+        let id: Option<u64> = cmd("GET")
+            .arg(user_name_key(name))
+            .query_async(&mut conn)
+            .await?;
+        Ok(id.and_then(Id::new_checked))
+    }
+
+    pub async fn member(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<Option<Member>> {
+        self.get_json(member_key(guild_id, user_id)).await
+    }
+
+    pub async fn channel(&self, id: Id<ChannelMarker>) -> Result<Option<Channel>> {
+        self.get_json(channel_key(id)).await
+    }
+
+    pub async fn role(&self, id: Id<RoleMarker>) -> Result<Option<Role>> {
+        self.get_json(role_key(id)).await
+    }
+
+    pub async fn guild_roles(&self, guild_id: Id<GuildMarker>) -> Result<Vec<Role>> {
+        let mut conn = self.pool.get().await?;
+        let ids: Vec<u64> = cmd("SMEMBERS")
+            .arg(guild_roles_key(guild_id))
+            .query_async(&mut conn)
+            .await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let keys: Vec<_> = ids
+            .into_iter()
+            .filter_map(Id::new_checked)
+            .map(role_key)
+            .collect();
+        let roles: Vec<Option<String>> = cmd("MGET").arg(keys).query_async(&mut conn).await?;
+        roles
+            .into_iter()
+            .flatten()
+            .map(|role| serde_json::from_str(&role).map_err(Into::into))
+            .collect()
+    }
+
+    fn cache_message(&self, pipe: &mut Pipeline, message: &Message) -> Result<()> {
+        pipe.cmd("SET")
+            .arg(message_key(message.id))
+            .arg(serde_json::to_string(message)?)
+            .arg("PX")
+            .arg(self.message_ttl.as_millis() as u64);
+        Ok(())
+    }
+
+    /// Edits only arrive as partial messages, so they're applied on top of
+    /// the cached copy (if there is one) without touching its expiry.
+    async fn update_message(&self, update: &MessageUpdate) -> Result<()> {
+        self.modify_json(&message_key(update.id), None, |message: Option<Message>| {
+            let mut message = message?;
+            if let Some(content) = &update.content {
+                message.content = content.clone();
+            }
+            if let Some(embeds) = &update.embeds {
+                message.embeds = embeds.clone();
+            }
+            if let Some(attachments) = &update.attachments {
+                message.attachments = attachments.clone();
+            }
+            if let Some(pinned) = update.pinned {
+                message.pinned = pinned;
+            }
+            if update.edited_timestamp.is_some() {
+                message.edited_timestamp = update.edited_timestamp;
+            }
+            Some(message)
+        })
+        .await
+    }
+
+    async fn update_member(&self, update: &MemberUpdate) -> Result<()> {
+        let key = member_key(update.guild_id, update.user.id);
+        self.modify_json(&key, Some(self.entity_ttl), |existing: Option<Member>| {
+            Some(Member {
+                avatar: update.avatar,
+                communication_disabled_until: update.communication_disabled_until,
+                deaf: update
+                    .deaf
+                    .or_else(|| existing.as_ref().map(|m| m.deaf))
+                    .unwrap_or_default(),
+                guild_id: update.guild_id,
+                joined_at: update.joined_at,
+                mute: update
+                    .mute
+                    .or_else(|| existing.as_ref().map(|m| m.mute))
+                    .unwrap_or_default(),
+                nick: update.nick.clone(),
+                pending: update.pending,
+                premium_since: update.premium_since,
+                roles: update.roles.clone(),
+                user: update.user.clone(),
+            })
+        })
+        .await?;
+
+        let mut pipe = pipe();
+        pipe.cmd("SADD")
+            .arg(guild_members_key(update.guild_id))
+            .arg(update.user.id.get());
+        self.expire(&mut pipe, guild_members_key(update.guild_id));
+        self.cache_user(&mut pipe, &update.user)?;
+        let mut conn = self.pool.get().await?;
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Replaces the JSON at `key` with what `modify` makes of it, watching the
+    /// key so an update another worker makes in between isn't overwritten.
+    /// `modify` returning `None` leaves the key alone. Without a `ttl` the
+    /// key's current expiry is kept.
+    async fn modify_json<T, F>(&self, key: &str, ttl: Option<Duration>, mut modify: F) -> Result<()>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnMut(Option<T>) -> Option<T>,
+    {
+        let mut conn = self.pool.get().await?;
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            cmd("WATCH")
+                .arg(key)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            let current: Option<String> = cmd("GET").arg(key).query_async(&mut conn).await?;
+            let modified = current
+                .map(|current| serde_json::from_str(&current))
+                .transpose()
+                .map(&mut modify)
+                .and_then(|value| value.map(|value| serde_json::to_string(&value)).transpose());
+            let value = match modified {
+                Ok(Some(value)) => value,
+                // Don't leave the connection watching the key for whoever
+                // gets it from the pool next
+                Ok(None) => {
+                    cmd("UNWATCH").query_async::<_, ()>(&mut conn).await?;
+                    return Ok(());
+                }
+                Err(why) => {
+                    cmd("UNWATCH").query_async::<_, ()>(&mut conn).await?;
+                    return Err(why.into());
+                }
+            };
+
+            let mut pipe = pipe();
+            pipe.atomic().cmd("SET").arg(key).arg(value);
+            match ttl {
+                Some(ttl) => pipe.arg("PX").arg(ttl.as_millis() as u64),
+                None => pipe.arg("KEEPTTL"),
+            };
+            // `None` when the key changed after `WATCH` and the write was
+            // dropped
+            let written: Option<()> = pipe.query_async(&mut conn).await?;
+            if written.is_some() {
+                return Ok(());
+            }
+        }
+
+        warn!(
+            "Gave up updating {} after {} conflicts",
+            key, MAX_UPDATE_ATTEMPTS
+        );
+        Ok(())
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, key: impl AsRef<str>) -> Result<Option<T>> {
+        let mut conn = self.pool.get().await?;
+        let value: Option<String> = cmd("GET").arg(key.as_ref()).query_async(&mut conn).await?;
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_json<T: Serialize>(&self, pipe: &mut Pipeline, key: String, value: &T) -> Result<()> {
+        pipe.cmd("SET")
+            .arg(key)
+            .arg(serde_json::to_string(value)?)
+            .arg("PX")
+            .arg(self.entity_ttl.as_millis() as u64);
+        Ok(())
+    }
+
+    /// Pushes back the expiry of a guild's index set along with its entries.
+    fn expire(&self, pipe: &mut Pipeline, key: String) {
+        pipe.cmd("PEXPIRE")
+            .arg(key)
+            .arg(self.entity_ttl.as_millis() as u64);
+    }
+
+    fn cache_user(&self, pipe: &mut Pipeline, user: &User) -> Result<()> {
+        self.set_json(pipe, user_key(user.id), user)?;
+        pipe.cmd("SET")
+            .arg(user_name_key(&user.name))
+            .arg(user.id.get())
+            .arg("PX")
+            .arg(self.entity_ttl.as_millis() as u64);
+        Ok(())
+    }
+
+    fn cache_member(&self, pipe: &mut Pipeline, member: &Member) -> Result<()> {
+        self.set_json(pipe, member_key(member.guild_id, member.user.id), member)?;
+        pipe.cmd("SADD")
+            .arg(guild_members_key(member.guild_id))
+            .arg(member.user.id.get());
+        self.expire(pipe, guild_members_key(member.guild_id));
+        self.cache_user(pipe, &member.user)
+    }
+
+    fn cache_channel(
+        &self,
+        pipe: &mut Pipeline,
+        guild_id: Id<GuildMarker>,
+        channel: &Channel,
+    ) -> Result<()> {
+        self.set_json(pipe, channel_key(channel.id), channel)?;
+        pipe.cmd("SADD")
+            .arg(guild_channels_key(guild_id))
+            .arg(channel.id.get());
+        self.expire(pipe, guild_channels_key(guild_id));
+        Ok(())
+    }
+
+    fn cache_role(
+        &self,
+        pipe: &mut Pipeline,
+        guild_id: Id<GuildMarker>,
+        role: &Role,
+    ) -> Result<()> {
+        self.set_json(pipe, role_key(role.id), role)?;
+        pipe.cmd("SADD")
+            .arg(guild_roles_key(guild_id))
+            .arg(role.id.get());
+        self.expire(pipe, guild_roles_key(guild_id));
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use crate::core::prelude::*;
+use twilight_http::Client as HttpClient;
+use twilight_model::application::interaction::application_command::{
+    CommandDataOption, CommandOptionValue,
+};
+use twilight_model::application::interaction::ApplicationCommand;
+use twilight_model::channel::embed::Embed;
+use twilight_model::channel::message::MessageFlags;
+use twilight_model::http::interaction::{
+    InteractionResponse, InteractionResponseData, InteractionResponseType,
+};
+
+/// An application command invocation, routed to the plugin that declared the
+/// command.
+#[derive(Debug, Clone)]
+pub struct CommandContext {
+    pub interaction: Box<ApplicationCommand>,
+    http: Arc<HttpClient>,
+}
+
+impl CommandContext {
+    pub fn new(interaction: Box<ApplicationCommand>, http: Arc<HttpClient>) -> Self {
+        Self { interaction, http }
+    }
+
+    /// Name of the invoked command.
+    pub fn name(&self) -> &str {
+        &self.interaction.data.name
+    }
+
+    /// Names of the invoked subcommand group and subcommand, outermost
+    /// first. Empty for commands without subcommands.
+    pub fn subcommand(&self) -> Vec<&str> {
+        let mut path = Vec::new();
+        let mut options = &self.interaction.data.options;
+        while let Some(option) = options.first() {
+            match &option.value {
+                CommandOptionValue::SubCommand(inner)
+                | CommandOptionValue::SubCommandGroup(inner) => {
+                    path.push(option.name.as_str());
+                    options = inner;
+                }
+                _ => break,
+            }
+        }
+        path
+    }
+
+    /// Options passed to the invoked (sub)command.
+    pub fn options(&self) -> &[CommandDataOption] {
+        let mut options = &self.interaction.data.options;
+        while let Some(option) = options.first() {
+            match &option.value {
+                CommandOptionValue::SubCommand(inner)
+                | CommandOptionValue::SubCommandGroup(inner) => options = inner,
+                _ => break,
+            }
+        }
+        options
+    }
+
+    pub fn option(&self, name: &str) -> Option<&CommandOptionValue> {
+        self.options()
+            .iter()
+            .find(|option| option.name == name)
+            .map(|option| &option.value)
+    }
+
+    pub fn string(&self, name: &str) -> Option<&str> {
+        match self.option(name) {
+            Some(CommandOptionValue::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.option(name) {
+            Some(CommandOptionValue::Integer(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        match self.option(name) {
+            Some(CommandOptionValue::Number(value)) => Some(value.0),
+            _ => None,
+        }
+    }
+
+    pub fn boolean(&self, name: &str) -> Option<bool> {
+        match self.option(name) {
+            Some(CommandOptionValue::Boolean(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn user(&self, name: &str) -> Option<Id<UserMarker>> {
+        match self.option(name) {
+            Some(CommandOptionValue::User(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn channel(&self, name: &str) -> Option<Id<ChannelMarker>> {
+        match self.option(name) {
+            Some(CommandOptionValue::Channel(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn role(&self, name: &str) -> Option<Id<RoleMarker>> {
+        match self.option(name) {
+            Some(CommandOptionValue::Role(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn guild_id(&self) -> Option<Id<GuildMarker>> {
+        self.interaction.guild_id
+    }
+
+    /// The user who invoked the command.
+    pub fn author_id(&self) -> Option<Id<UserMarker>> {
+        self.interaction.author_id()
+    }
+
+    /// Sends the initial response to the interaction. Discord only accepts
+    /// one, within 3 seconds of the invocation.
+    pub async fn respond(
+        &self,
+        kind: InteractionResponseType,
+        data: Option<InteractionResponseData>,
+    ) -> Result<()> {
+        let response = InteractionResponse { kind, data };
+        self.http
+            .interaction(self.interaction.application_id)
+            .create_response(self.interaction.id, &self.interaction.token, &response)
+            .exec()
+            .await?;
+        Ok(())
+    }
+
+    /// Replies with a message visible to everyone in the channel.
+    pub async fn reply(&self, content: impl Into<String>) -> Result<()> {
+        self.respond(
+            InteractionResponseType::ChannelMessageWithSource,
+            Some(InteractionResponseData {
+                content: Some(content.into()),
+                allowed_mentions: Some(AllowedMentions::builder().build()),
+                ..Default::default()
+            }),
+        )
+        .await
+    }
+
+    /// Replies with a message only the invoking user can see.
+    pub async fn reply_ephemeral(&self, content: impl Into<String>) -> Result<()> {
+        self.respond(
+            InteractionResponseType::ChannelMessageWithSource,
+            Some(InteractionResponseData {
+                content: Some(content.into()),
+                flags: Some(MessageFlags::EPHEMERAL),
+                ..Default::default()
+            }),
+        )
+        .await
+    }
+
+    pub async fn reply_embeds(&self, embeds: Vec<Embed>) -> Result<()> {
+        self.respond(
+            InteractionResponseType::ChannelMessageWithSource,
+            Some(InteractionResponseData {
+                embeds: Some(embeds),
+                allowed_mentions: Some(AllowedMentions::builder().build()),
+                ..Default::default()
+            }),
+        )
+        .await
+    }
+
+    /// Acknowledges the command to answer later with `edit_response`, for
+    /// anything that could take longer than 3 seconds.
+    pub async fn defer(&self, ephemeral: bool) -> Result<()> {
+        self.respond(
+            InteractionResponseType::DeferredChannelMessageWithSource,
+            Some(InteractionResponseData {
+                flags: ephemeral.then_some(MessageFlags::EPHEMERAL),
+                ..Default::default()
+            }),
+        )
+        .await
+    }
+
+    /// Replaces the content of the response, e.g. after `defer`.
+    pub async fn edit_response(&self, content: &str) -> Result<()> {
+        self.http
+            .interaction(self.interaction.application_id)
+            .update_response(&self.interaction.token)
+            .content(Some(content))?
+            .exec()
+            .await?;
+        Ok(())
+    }
+}
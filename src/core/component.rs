@@ -0,0 +1,128 @@
+use crate::core::prelude::*;
+use twilight_http::Client as HttpClient;
+use twilight_model::application::component::ComponentType;
+use twilight_model::application::interaction::MessageComponentInteraction;
+use twilight_model::channel::message::MessageFlags;
+use twilight_model::http::interaction::{
+    InteractionResponse, InteractionResponseData, InteractionResponseType,
+};
+
+/// Separates a plugin's namespace from the rest of a component's custom id,
+/// e.g. `giveaways:enter`.
+pub const CUSTOM_ID_SEPARATOR: char = ':';
+
+/// Namespace part of a custom id, which decides the plugin it's routed to.
+pub fn namespace(custom_id: &str) -> &str {
+    custom_id
+        .split_once(CUSTOM_ID_SEPARATOR)
+        .map_or(custom_id, |(namespace, _)| namespace)
+}
+
+/// A button click or select menu choice on a component in a plugin's
+/// namespace.
+#[derive(Debug, Clone)]
+pub struct ComponentContext {
+    pub interaction: Box<MessageComponentInteraction>,
+    http: Arc<HttpClient>,
+}
+
+impl ComponentContext {
+    pub fn new(interaction: Box<MessageComponentInteraction>, http: Arc<HttpClient>) -> Self {
+        Self { interaction, http }
+    }
+
+    /// The full custom id, including the namespace.
+    pub fn custom_id(&self) -> &str {
+        &self.interaction.data.custom_id
+    }
+
+    /// The custom id with the plugin's namespace stripped.
+    pub fn id(&self) -> &str {
+        let custom_id = self.custom_id();
+        custom_id
+            .split_once(CUSTOM_ID_SEPARATOR)
+            .map_or("", |(_, id)| id)
+    }
+
+    pub fn kind(&self) -> ComponentType {
+        self.interaction.data.component_type
+    }
+
+    /// Options picked in a select menu, empty for buttons.
+    pub fn values(&self) -> &[String] {
+        &self.interaction.data.values
+    }
+
+    pub fn guild_id(&self) -> Option<Id<GuildMarker>> {
+        self.interaction.guild_id
+    }
+
+    /// The user who used the component.
+    pub fn author_id(&self) -> Option<Id<UserMarker>> {
+        self.interaction.author_id()
+    }
+
+    /// The message the component is attached to.
+    pub fn message_id(&self) -> Id<MessageMarker> {
+        self.interaction.message.id
+    }
+
+    /// Sends the initial response to the interaction. Discord only accepts
+    /// one, within 3 seconds of the click.
+    pub async fn respond(
+        &self,
+        kind: InteractionResponseType,
+        data: Option<InteractionResponseData>,
+    ) -> Result<()> {
+        let response = InteractionResponse { kind, data };
+        self.http
+            .interaction(self.interaction.application_id)
+            .create_response(self.interaction.id, &self.interaction.token, &response)
+            .exec()
+            .await?;
+        Ok(())
+    }
+
+    /// Acknowledges the interaction without changing anything.
+    pub async fn ack(&self) -> Result<()> {
+        self.respond(InteractionResponseType::DeferredUpdateMessage, None)
+            .await
+    }
+
+    /// Edits the message the component is attached to.
+    pub async fn update_message(&self, data: InteractionResponseData) -> Result<()> {
+        self.respond(InteractionResponseType::UpdateMessage, Some(data))
+            .await
+    }
+
+    /// Replies with a message only the clicking user can see.
+    pub async fn reply_ephemeral(&self, content: impl Into<String>) -> Result<()> {
+        self.respond(
+            InteractionResponseType::ChannelMessageWithSource,
+            Some(InteractionResponseData {
+                content: Some(content.into()),
+                flags: Some(MessageFlags::EPHEMERAL),
+                ..Default::default()
+            }),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_is_everything_before_the_first_separator() {
+        assert_eq!(namespace("giveaways:enter"), "giveaways");
+        assert_eq!(namespace("timers:remind:extra"), "timers");
+        assert_eq!(namespace(":enter"), "");
+    }
+
+    #[test]
+    fn namespace_without_separator_is_the_whole_id() {
+        assert_eq!(namespace("giveaways"), "giveaways");
+        assert_eq!(namespace(""), "");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::core::prelude::*;
+use crate::model::WorkerConfig;
+use lapin::message::Delivery;
+use lapin::options::{BasicAckOptions, BasicNackOptions, BasicPublishOptions};
+use lapin::types::{AMQPValue, FieldArray, FieldTable, LongString};
+use lapin::Channel;
+use tracing::{error, warn};
+
+/// Header carrying how many times a delivery has already been retried.
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+/// Header carrying the plugins a retried or dead-lettered delivery failed in.
+/// Only those run when it's processed again.
+pub const PLUGINS_HEADER: &str = "x-plugins";
+/// Header carrying the error a dead-lettered delivery failed with.
+pub const ERROR_HEADER: &str = "x-error";
+
+/// A plugin that failed to process a delivery.
+#[derive(Debug)]
+pub struct PluginFailure {
+    pub plugin: &'static str,
+    pub error: Error,
+}
+
+/// Number of times this delivery has been retried by `settle`.
+pub fn retry_count(delivery: &Delivery) -> u32 {
+    match header(delivery, RETRY_COUNT_HEADER) {
+        Some(AMQPValue::LongUInt(count)) => *count,
+        _ => 0,
+    }
+}
+
+/// Plugins this delivery should be handed to, `None` for every plugin
+/// subscribed to it.
+pub fn retry_plugins(delivery: &Delivery) -> Option<Vec<String>> {
+    match header(delivery, PLUGINS_HEADER) {
+        Some(AMQPValue::FieldArray(plugins)) => Some(
+            plugins
+                .as_slice()
+                .iter()
+                .filter_map(|plugin| match plugin {
+                    AMQPValue::LongString(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+fn header<'a>(delivery: &'a Delivery, name: &str) -> Option<&'a AMQPValue> {
+    delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(name))
+}
+
+/// Acks, retries or dead-letters a delivery depending on which plugins failed
+/// to process it.
+///
+/// Plugins that failed with a transient error get the delivery again after
+/// `retry_delay`, through the retry queue, until `max_retries` is reached.
+/// Plugins that succeeded don't see it twice. Everything else, including
+/// every failure of a delivery that isn't `retryable`, is routed to the
+/// dead-letter queue, or rejected without requeueing when none is
+/// configured.
+///
+/// Returns what happened to the delivery: `acked`, `retried`,
+/// `dead_lettered` or `dropped`.
+pub async fn settle(
+    delivery: Delivery,
+    failures: Vec<PluginFailure>,
+    retryable: bool,
+    channel: &Channel,
+    config: &WorkerConfig,
+) -> Result<&'static str> {
+    if failures.is_empty() {
+        delivery.ack(BasicAckOptions::default()).await?;
+        return Ok("acked");
+    }
+
+    let retries = retry_count(&delivery);
+    let (retry, dead): (Vec<_>, Vec<_>) = failures.into_iter().partition(|failure| {
+        retryable && failure.error.is_transient() && retries < config.max_retries
+    });
+
+    if !retry.is_empty() {
+        warn!(
+            "Retrying delivery in {}s after transient errors (attempt {}/{}): {:?}",
+            config.retry_delay,
+            retries + 1,
+            config.max_retries,
+            retry
+        );
+        let mut headers = FieldTable::default();
+        headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongUInt(retries + 1));
+        headers.insert(PLUGINS_HEADER.into(), plugin_names(&retry));
+        republish(&delivery, channel, &config.retry_queue(), headers).await?;
+    }
+
+    if dead.is_empty() {
+        delivery.ack(BasicAckOptions::default()).await?;
+        return Ok("retried");
+    }
+    let why = dead
+        .iter()
+        .map(|failure| format!("{}: {:?}", failure.plugin, failure.error))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut headers = FieldTable::default();
+    headers.insert(PLUGINS_HEADER.into(), plugin_names(&dead));
+    move_to_dead_letter(delivery, why, headers, channel, config).await
+}
+
+/// Moves a delivery that can't be processed out of `rabbit_queue`, returning
+/// whether it was `dead_lettered` or `dropped`.
+pub async fn dead_letter(
+    delivery: Delivery,
+    why: &Error,
+    channel: &Channel,
+    config: &WorkerConfig,
+) -> Result<&'static str> {
+    move_to_dead_letter(
+        delivery,
+        format!("{:?}", why),
+        FieldTable::default(),
+        channel,
+        config,
+    )
+    .await
+}
+
+async fn move_to_dead_letter(
+    delivery: Delivery,
+    why: String,
+    mut headers: FieldTable,
+    channel: &Channel,
+    config: &WorkerConfig,
+) -> Result<&'static str> {
+    match &config.rabbit_dead_letter_queue {
+        Some(queue) => {
+            error!("Dead-lettering delivery to {}: {}", queue, why);
+            headers.insert(
+                RETRY_COUNT_HEADER.into(),
+                AMQPValue::LongUInt(retry_count(&delivery)),
+            );
+            headers.insert(
+                ERROR_HEADER.into(),
+                AMQPValue::LongString(LongString::from(why)),
+            );
+            republish(&delivery, channel, queue, headers).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+            Ok("dead_lettered")
+        }
+        None => {
+            error!("Dropping delivery: {}", why);
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: false,
+                    ..Default::default()
+                })
+                .await?;
+            Ok("dropped")
+        }
+    }
+}
+
+fn plugin_names(failures: &[PluginFailure]) -> AMQPValue {
+    let mut names = FieldArray::default();
+    for failure in failures {
+        names.push(AMQPValue::LongString(LongString::from(failure.plugin)));
+    }
+    AMQPValue::FieldArray(names)
+}
+
+/// Publishes a copy of the delivery to `queue`, keeping its properties and
+/// headers, with `headers` added on top.
+async fn republish(
+    delivery: &Delivery,
+    channel: &Channel,
+    queue: &str,
+    headers: FieldTable,
+) -> Result<()> {
+    let mut merged = delivery.properties.headers().clone().unwrap_or_default();
+    for (name, value) in headers.inner() {
+        merged.insert(name.clone(), value.clone());
+    }
+    channel
+        .basic_publish(
+            "",
+            queue,
+            BasicPublishOptions::default(),
+            &delivery.data,
+            delivery.properties.clone().with_headers(merged),
+        )
+        .await?
+        .await?;
+    Ok(())
+}
@@ -1,8 +1,12 @@
 use deadpool_redis::redis::RedisError;
-use mongodb::error::Error as MongoError;
+#[cfg(feature = "mongo")]
+use mongodb::error::{Error as MongoError, ErrorKind as MongoErrorKind};
 use std::error::Error as StdError;
-use twilight_embed_builder::EmbedError;
+use twilight_http::error::ErrorType;
+use twilight_validate::embed::EmbedValidationError;
 use twilight_validate::message::MessageValidationError;
+// Variants are named after what failed, e.g. `RedisError`
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Environment variable '{0}' not found.")]
@@ -21,25 +25,32 @@ pub enum Error {
     InvalidPayload(String),
 
     #[error("Embed failed to build.")]
-    EmbedFailed(EmbedError),
+    EmbedFailed(EmbedValidationError),
 
-    #[error("Failed to load config")]
+    #[error("Failed to load config: {0}")]
     ConfigError(#[from] config::ConfigError),
 
+    #[error("Invalid config:\n  {}", .0.join("\n  "))]
+    InvalidConfig(Vec<String>),
+
     #[error("Twilight raised an error")]
     TwilightError(#[from] Box<dyn StdError + Send + Sync>),
 
+    #[cfg(feature = "mongo")]
     #[error("MongoDB raised an error")]
     MongoError(#[from] MongoError),
 
+    #[cfg(feature = "mongo")]
     #[error("Mongodb failed to serialize object")]
     MongoSerializationFailed(#[from] mongodb::bson::ser::Error),
 
+    #[cfg(feature = "mongo")]
     #[error("Mongodb failed to deserialize object")]
     MongoDeserializationFailed(#[from] mongodb::bson::de::Error),
 
+    /// Boxed, it's by far the largest error and would bloat every `Result`.
     #[error("TwilightHttp raised an error while generating an api request.")]
-    TwilightHttpError(#[from] twilight_http::Error),
+    TwilightHttpError(#[source] Box<twilight_http::Error>),
 
     #[error("TwilightHttp raised an error while creating a message.")]
     TwilightMessageCreateFailed(#[from] MessageValidationError),
@@ -53,7 +64,98 @@ pub enum Error {
     #[error("Redis command failed")]
     RedisFailed(#[from] RedisError),
 
+    #[error("Failed to get a connection from the redis pool")]
+    RedisPoolFailed(#[from] deadpool_redis::PoolError),
+
+    #[error("RabbitMQ raised an error")]
+    RabbitError(#[from] lapin::Error),
+
+    #[error("RabbitMQ consumer was cancelled")]
+    ConsumerCancelled,
+
+    #[error("Plugin did not finish within {0:?}")]
+    PluginTimedOut(std::time::Duration),
+
+    #[error("Plugin panicked: {0}")]
+    PluginPanicked(String),
+
+    #[error("Lease {0} was taken over by another worker")]
+    LeaseLost(String),
+
+    #[error("HTTP server failed")]
+    HttpServerError(#[from] hyper::Error),
+
     #[cfg(feature = "tagscript")]
     #[error("TagScript processing failed")]
     TagScriptError(#[from] tagscript::Error),
 }
+
+impl From<twilight_http::Error> for Error {
+    fn from(error: twilight_http::Error) -> Self {
+        Error::TwilightHttpError(Box::new(error))
+    }
+}
+
+impl Error {
+    /// Whether retrying the same work later could succeed: connectivity
+    /// problems with Mongo, Redis or RabbitMQ, and Discord 5xx responses.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            #[cfg(feature = "mongo")]
+            Error::MongoError(e) => matches!(
+                *e.kind,
+                MongoErrorKind::Io(_)
+                    | MongoErrorKind::ServerSelection { .. }
+                    | MongoErrorKind::ConnectionPoolCleared { .. }
+            ),
+            Error::RedisFailed(e) => e.is_io_error() || e.is_timeout() || e.is_connection_dropped(),
+            Error::RedisPoolFailed(_)
+            | Error::RabbitError(_)
+            | Error::ConsumerCancelled
+            | Error::PluginTimedOut(_) => true,
+            Error::TwilightHttpError(e) => match e.kind() {
+                ErrorType::Response { status, .. } => status.is_server_error(),
+                ErrorType::ServiceUnavailable { .. }
+                | ErrorType::RequestTimedOut
+                | ErrorType::RequestError => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Name of the variant, used to tag error reports.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            Error::EnvironmentVariableNotFound(_) => "EnvironmentVariableNotFound",
+            Error::HeaderNotFound(_) => "HeaderNotFound",
+            Error::SignalError(_) => "SignalError",
+            Error::JsonFailed(_) => "JsonFailed",
+            Error::InvalidPayload(_) => "InvalidPayload",
+            Error::EmbedFailed(_) => "EmbedFailed",
+            Error::ConfigError(_) => "ConfigError",
+            Error::InvalidConfig(_) => "InvalidConfig",
+            Error::TwilightError(_) => "TwilightError",
+            #[cfg(feature = "mongo")]
+            Error::MongoError(_) => "MongoError",
+            #[cfg(feature = "mongo")]
+            Error::MongoSerializationFailed(_) => "MongoSerializationFailed",
+            #[cfg(feature = "mongo")]
+            Error::MongoDeserializationFailed(_) => "MongoDeserializationFailed",
+            Error::TwilightHttpError(_) => "TwilightHttpError",
+            Error::TwilightMessageCreateFailed(_) => "TwilightMessageCreateFailed",
+            Error::DiscordDeserializeFailed(_) => "DiscordDeserializeFailed",
+            Error::ParseIntError(_) => "ParseIntError",
+            Error::RedisFailed(_) => "RedisFailed",
+            Error::RedisPoolFailed(_) => "RedisPoolFailed",
+            Error::RabbitError(_) => "RabbitError",
+            Error::ConsumerCancelled => "ConsumerCancelled",
+            Error::PluginTimedOut(_) => "PluginTimedOut",
+            Error::PluginPanicked(_) => "PluginPanicked",
+            Error::LeaseLost(_) => "LeaseLost",
+            Error::HttpServerError(_) => "HttpServerError",
+            #[cfg(feature = "tagscript")]
+            Error::TagScriptError(_) => "TagScriptError",
+        }
+    }
+}
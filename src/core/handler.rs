@@ -1,378 +1,693 @@
+use crate::core::delivery::{self, PluginFailure};
 use crate::core::prelude::*;
+use crate::core::serial::SerialExecutor;
+use crate::core::{CommandContext, ComponentContext};
+use crate::model::{EventOrdering, WorkerConfig};
 use crate::Context;
+use futures::future::FutureExt;
 use futures::stream::StreamExt;
-use lapin::options::BasicAckOptions;
-use lapin::Consumer;
+use lapin::message::Delivery;
+use lapin::options::{BasicAckOptions, BasicCancelOptions};
+use lapin::{Channel, Consumer};
+use prometheus::IntGauge;
+use sentry::{Breadcrumb, Hub, SentryFutureExt};
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Duration};
 
 use serde::de::DeserializeSeed;
-use tracing::error;
+use tracing::{error, warn};
+use twilight_model::application::interaction::{
+    ApplicationCommand, Interaction, MessageComponentInteraction,
+};
 use twilight_model::gateway::event::{GatewayEvent, GatewayEventDeserializer};
-use twilight_model::gateway::payload::incoming::*;
 use twilight_model::gateway::OpCode;
+
+/// Counts an event in `events_in_flight` until dropped, which also happens
+/// when its task panics or is cancelled.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
 pub struct EventHandler {
     consumer: Consumer,
+    /// Channel the consumer lives on, also used to requeue and dead-letter
+    /// deliveries
+    channel: Channel,
+    config: Arc<WorkerConfig>,
+    /// Bounds the events processed at once to `max_concurrent_events`.
+    limiter: Arc<Semaphore>,
+    serial: SerialExecutor,
     ctx: Context,
 }
 
 impl EventHandler {
-    pub fn new(ctx: Context, consumer: Consumer) -> Self {
-        Self { consumer, ctx }
+    pub fn new(ctx: Context, consumer: Consumer, channel: Channel, config: WorkerConfig) -> Self {
+        Self {
+            consumer,
+            channel,
+            limiter: Arc::new(Semaphore::new(config.max_concurrent_events)),
+            serial: SerialExecutor::new(config.max_waiting_per_key),
+            config: Arc::new(config),
+            ctx,
+        }
     }
 
-    pub async fn start(&mut self) {
-        while let Some(delivery) = self.consumer.next().await {
-            let mut delivery = delivery.expect("error in consumer");
-
-            let (op, seq, event_type) = {
-                let json = std::str::from_utf8_mut(&mut delivery.data).expect("invalid utf8");
-                if let Some(deserializer) = GatewayEventDeserializer::from_json(json) {
-                    let (op, seq, event_type) = deserializer.into_parts();
-
-                    // Unfortunately lifetimes and mutability requirements
-                    // conflict here if we return an immutable reference to the
-                    // event type, so we're going to have to take ownership of
-                    // this if we don't want to do anything too dangerous. It
-                    // should be a good trade-off either way.
-                    // Via twilight-rs/gateway
-                    (op, seq, event_type.map(ToOwned::to_owned))
-                } else {
-                    error!(json = json, "received payload without opcode",);
-                    continue; // Shouldn't be doing this but gateway should be already verifying data
+    /// Swaps in a fresh consumer after the previous one failed.
+    pub fn set_consumer(&mut self, channel: Channel, consumer: Consumer) {
+        self.channel = channel;
+        self.consumer = consumer;
+    }
+
+    /// Consumes deliveries until shutdown is triggered. Event tasks may still
+    /// be running when this returns, wait on `Context::shutdown` to drain
+    /// them.
+    ///
+    /// Returns an error when the consumer or its channel breaks, in which case
+    /// the caller should reconnect and call `start` again.
+    pub async fn start(&mut self) -> Result<()> {
+        self.ctx.health.set_consuming(true);
+        let result = self.consume().await;
+        self.ctx.health.set_consuming(false);
+        result
+    }
+
+    async fn consume(&mut self) -> Result<()> {
+        let mut shutdown = self.ctx.shutdown.clone();
+        loop {
+            let delivery = tokio::select! {
+                delivery = self.consumer.next() => match delivery {
+                    Some(delivery) => delivery?,
+                    None => return Err(Error::ConsumerCancelled),
+                },
+                _ = shutdown.recv() => {
+                    event!(Level::INFO, "Cancelling consumer");
+                    if let Err(why) = self
+                        .channel
+                        .basic_cancel(self.consumer.tag().as_str(), BasicCancelOptions::default())
+                        .await
+                    {
+                        error!("Failed to cancel consumer: {:?}", why);
+                    }
+                    return Ok(());
                 }
             };
 
-            let gateway_event = if op == OpCode::HeartbeatAck as u8 {
-                GatewayEvent::HeartbeatAck
-            } else if op == OpCode::Reconnect as u8 {
-                GatewayEvent::Reconnect
-            } else {
-                // Json gateway deserializer from twilight
-
-                let gateway_deserializer =
-                    GatewayEventDeserializer::new(op, seq, event_type.as_deref());
+            let event = match parse_event(&delivery.data) {
+                Ok(event) => event,
+                Err(why) => {
+                    self.reject_malformed(delivery, why).await?;
+                    continue;
+                }
+            };
 
-                let mut json_deserializer =
-                    serde_json::Deserializer::from_slice(&mut delivery.data);
+            let event_type = event.kind().name().unwrap_or("UNKNOWN");
+            self.ctx.metrics.delivery(event_type, "consumed");
+
+            let subscribed = self
+                .ctx
+                .plugin_config
+                .read()
+                .await
+                .is_subscribed(event.kind());
+            let key = ordering_key(&event, self.config.event_ordering);
+            let limiter = self.limiter.clone();
+
+            // No plugin wants it, but it may still change the cache
+            if !subscribed {
+                delivery.ack(BasicAckOptions::default()).await?;
+                self.ctx.metrics.delivery(event_type, "acked");
+                let ctx = self.ctx.clone();
+                self.ctx.shutdown.spawn(self.serial.queue(key, async move {
+                    if let Ok(_permit) = limiter.acquire_owned().await {
+                        update_cache(&event, &ctx).await;
+                    }
+                }));
+                continue;
+            }
 
-                gateway_deserializer
-                    .deserialize(&mut json_deserializer)
-                    .expect("Could not deserialize ws data to object")
-            };
+            let plugin_timeout = Duration::from_secs(self.config.plugin_timeout);
+            let only = delivery::retry_plugins(&delivery);
+            let in_flight = InFlight::new(&self.ctx.metrics.events_in_flight);
+            // The permit is only taken once the events queued before this one
+            // under the same key are done, so a busy guild or channel waiting
+            // on itself doesn't hold up everyone else's capacity
+            if !self.config.ack_after_processing {
+                let ctx = self.ctx.clone();
+                self.ctx.shutdown.spawn(self.serial.queue(key, async move {
+                    let _in_flight = in_flight;
+                    let _permit = match limiter.acquire_owned().await {
+                        Ok(permit) => permit,
+                        Err(_) => return,
+                    };
+                    // Acked when its turn comes rather than when received, so
+                    // the prefetch window still bounds how many events wait here
+                    if let Err(why) = delivery.ack(BasicAckOptions::default()).await {
+                        error!("Failed to ack delivery: {:?}", why);
+                        return;
+                    }
+                    // We've got the event the rest is up to sentry to monitor
+                    ctx.metrics.delivery(event_type, "acked");
+                    update_cache(&event, &ctx).await;
+                    // Failures are already counted per plugin by `run_plugin`
+                    handle_event(event, ctx, plugin_timeout, only).await;
+                }));
+                continue;
+            }
 
-            let event = Event::from(gateway_event);
+            // An interaction's token expires long before a retry would run,
+            // and plugins that already answered it would answer again
+            let retryable = !matches!(event, Event::InteractionCreate(_));
+            let ctx = self.ctx.clone();
+            let channel = self.channel.clone();
+            let config = self.config.clone();
+            self.ctx.shutdown.spawn(self.serial.queue(key, async move {
+                let failures = {
+                    let _in_flight = in_flight;
+                    let _permit = match limiter.acquire_owned().await {
+                        Ok(permit) => permit,
+                        Err(_) => return,
+                    };
+                    // Retries were already cached the first time round
+                    if only.is_none() {
+                        update_cache(&event, &ctx).await;
+                    }
+                    handle_event(event, ctx.clone(), plugin_timeout, only).await
+                };
+                match delivery::settle(delivery, failures, retryable, &channel, &config).await {
+                    Ok(status) => ctx.metrics.delivery(event_type, status),
+                    Err(why) => error!("Failed to settle delivery: {:?}", why),
+                }
+            }));
+        }
+    }
 
-            self.ctx.cache.update(&event);
-            delivery.ack(BasicAckOptions::default()).await.expect("ack"); // We've got the event the rest is up to sentry to monitor
-            tokio::spawn(handle_event(event, self.ctx.clone()));
+    /// Reports a delivery we couldn't make sense of and moves it out of the
+    /// queue so it isn't redelivered forever.
+    async fn reject_malformed(&self, delivery: Delivery, why: Error) -> Result<()> {
+        self.ctx.metrics.delivery("UNKNOWN", "malformed");
+        let mut payload = String::from_utf8_lossy(&delivery.data).into_owned();
+        if payload.len() > MAX_REPORTED_PAYLOAD {
+            let mut end = MAX_REPORTED_PAYLOAD;
+            while !payload.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.truncate(end);
         }
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("error", why.variant());
+                scope.set_extra("payload", payload.clone().into());
+            },
+            || error!(payload = %payload, "Received malformed delivery: {:?}", why),
+        );
+
+        delivery::dead_letter(delivery, &why, &self.channel, &self.config).await?;
+        Ok(())
+    }
+}
+
+/// Applies an event to the shared cache. A stale cache isn't worth failing
+/// the event over, so errors are only logged.
+async fn update_cache(event: &Event, ctx: &Context) {
+    if let Err(why) = ctx.cache.update(event).await {
+        warn!(
+            "Failed to update cache with {}: {:?}",
+            event.kind().name().unwrap_or("UNKNOWN"),
+            why
+        );
     }
 }
 
-async fn handle_event(event: Event, ctx: Context) -> Result<()> {
-    let plugin_config = {
-        let c = ctx.clone();
-        c.plugin_config
+/// Largest chunk of a malformed payload attached to its Sentry report.
+const MAX_REPORTED_PAYLOAD: usize = 16 * 1024;
+
+/// Deserializes a raw gateway payload forwarded by the gateway pods.
+fn parse_event(data: &[u8]) -> Result<Event> {
+    let json = std::str::from_utf8(data)
+        .map_err(|e| Error::InvalidPayload(format!("payload is not utf8: {}", e)))?;
+
+    let (op, seq, event_type) = {
+        let deserializer = GatewayEventDeserializer::from_json(json)
+            .ok_or_else(|| Error::InvalidPayload("payload has no opcode".to_string()))?;
+        let (op, seq, event_type) = deserializer.into_parts();
+
+        // Unfortunately lifetimes and mutability requirements
+        // conflict here if we return an immutable reference to the
+        // event type, so we're going to have to take ownership of
+        // this if we don't want to do anything too dangerous. It
+        // should be a good trade-off either way.
+        // Via twilight-rs/gateway
+        (op, seq, event_type.map(ToOwned::to_owned))
     };
 
-    match &event {
-        Event::MessageCreate(message) => {
-            if let Some(guild_id) = message.guild_id {
-                let plugins: Vec<_> = {
-                    let r1 = plugin_config.read().await;
+    let gateway_event = if op == OpCode::HeartbeatAck as u8 {
+        GatewayEvent::HeartbeatAck
+    } else if op == OpCode::Reconnect as u8 {
+        GatewayEvent::Reconnect
+    } else {
+        // Json gateway deserializer from twilight
 
-                    r1.get_plugins(&ctx, guild_id).await
-                };
-                event!(
-                    Level::DEBUG,
-                    "Got Plugins: ({:#?}) in {}",
-                    plugins,
-                    guild_id
-                );
+        let gateway_deserializer = GatewayEventDeserializer::new(op, seq, event_type.as_deref());
 
-                for plugin in plugins.iter() {
-                    match plugin.on_event(event.clone(), ctx.clone()).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            event!(
-                                Level::ERROR,
-                                "error in plugin ({}): {:#?}",
-                                e,
-                                plugin.name()
-                            );
-                        }
-                    };
-                }
-            }
-        }
+        let mut json_deserializer = serde_json::Deserializer::from_slice(data);
 
-        Event::MessageUpdate(message) => {
-            if let Some(guild_id) = message.guild_id {
-                let plugins: Vec<_> = {
-                    let r1 = plugin_config.read().await;
+        gateway_deserializer.deserialize(&mut json_deserializer)?
+    };
 
-                    r1.get_plugins(&ctx, guild_id).await
-                };
-                event!(
-                    Level::DEBUG,
-                    "Got Plugins: ({:#?}) in {}",
-                    plugins,
-                    guild_id
-                );
+    Ok(Event::from(gateway_event))
+}
 
-                for plugin in plugins.iter() {
-                    match plugin.on_event(event.clone(), ctx.clone()).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            event!(
-                                Level::ERROR,
-                                "error in plugin ({}): {:#?}",
-                                e,
-                                plugin.name()
-                            );
-                        }
-                    };
+/// Resolves the guild an event belongs to, returning `None` for events that
+/// aren't scoped to a guild (DMs, shard lifecycle, user updates, ...).
+pub fn guild_id(event: &Event) -> Option<Id<GuildMarker>> {
+    match event {
+        Event::BanAdd(e) => Some(e.guild_id),
+        Event::BanRemove(e) => Some(e.guild_id),
+        Event::ChannelCreate(e) => e.0.guild_id,
+        Event::ChannelDelete(e) => e.0.guild_id,
+        Event::ChannelPinsUpdate(e) => e.guild_id,
+        Event::ChannelUpdate(e) => e.0.guild_id,
+        Event::GuildCreate(e) => Some(e.0.id),
+        Event::GuildDelete(e) => Some(e.id),
+        Event::GuildEmojisUpdate(e) => Some(e.guild_id),
+        Event::GuildIntegrationsUpdate(e) => Some(e.guild_id),
+        Event::GuildStickersUpdate(e) => Some(e.guild_id),
+        Event::GuildUpdate(e) => Some(e.0.id),
+        Event::IntegrationCreate(e) => e.0.guild_id,
+        Event::IntegrationDelete(e) => Some(e.guild_id),
+        Event::IntegrationUpdate(e) => e.0.guild_id,
+        Event::InteractionCreate(e) => e.0.guild_id(),
+        Event::InviteCreate(e) => Some(e.guild_id),
+        Event::InviteDelete(e) => Some(e.guild_id),
+        Event::MemberAdd(e) => Some(e.0.guild_id),
+        Event::MemberChunk(e) => Some(e.guild_id),
+        Event::MemberRemove(e) => Some(e.guild_id),
+        Event::MemberUpdate(e) => Some(e.guild_id),
+        Event::MessageCreate(e) => e.0.guild_id,
+        Event::MessageDelete(e) => e.guild_id,
+        Event::MessageDeleteBulk(e) => e.guild_id,
+        Event::MessageUpdate(e) => e.guild_id,
+        Event::PresenceUpdate(e) => Some(e.guild_id),
+        Event::ReactionAdd(e) => e.0.guild_id,
+        Event::ReactionRemove(e) => e.0.guild_id,
+        Event::ReactionRemoveAll(e) => e.guild_id,
+        Event::ReactionRemoveEmoji(e) => Some(e.guild_id),
+        Event::RoleCreate(e) => Some(e.guild_id),
+        Event::RoleDelete(e) => Some(e.guild_id),
+        Event::RoleUpdate(e) => Some(e.guild_id),
+        Event::StageInstanceCreate(e) => Some(e.0.guild_id),
+        Event::StageInstanceDelete(e) => Some(e.0.guild_id),
+        Event::StageInstanceUpdate(e) => Some(e.0.guild_id),
+        Event::ThreadCreate(e) => e.0.guild_id,
+        Event::ThreadDelete(e) => Some(e.guild_id),
+        Event::ThreadListSync(e) => Some(e.guild_id),
+        Event::ThreadMembersUpdate(e) => Some(e.guild_id),
+        Event::ThreadUpdate(e) => e.0.guild_id,
+        Event::TypingStart(e) => e.guild_id,
+        Event::UnavailableGuild(e) => Some(e.id),
+        Event::VoiceServerUpdate(e) => e.guild_id,
+        Event::VoiceStateUpdate(e) => e.0.guild_id,
+        Event::WebhooksUpdate(e) => Some(e.guild_id),
+        _ => None,
+    }
+}
+
+/// Resolves the channel an event happened in, for events that have one.
+pub fn channel_id(event: &Event) -> Option<Id<ChannelMarker>> {
+    match event {
+        Event::ChannelCreate(e) => Some(e.0.id),
+        Event::ChannelDelete(e) => Some(e.0.id),
+        Event::ChannelPinsUpdate(e) => Some(e.channel_id),
+        Event::ChannelUpdate(e) => Some(e.0.id),
+        Event::InteractionCreate(e) => match &e.0 {
+            Interaction::ApplicationCommand(command) => Some(command.channel_id),
+            Interaction::ApplicationCommandAutocomplete(command) => Some(command.channel_id),
+            Interaction::MessageComponent(component) => Some(component.channel_id),
+            Interaction::ModalSubmit(modal) => Some(modal.channel_id),
+            _ => None,
+        },
+        Event::InviteCreate(e) => Some(e.channel_id),
+        Event::InviteDelete(e) => Some(e.channel_id),
+        Event::MessageCreate(e) => Some(e.0.channel_id),
+        Event::MessageDelete(e) => Some(e.channel_id),
+        Event::MessageDeleteBulk(e) => Some(e.channel_id),
+        Event::MessageUpdate(e) => Some(e.channel_id),
+        Event::ReactionAdd(e) => Some(e.0.channel_id),
+        Event::ReactionRemove(e) => Some(e.0.channel_id),
+        Event::ReactionRemoveAll(e) => Some(e.channel_id),
+        Event::ReactionRemoveEmoji(e) => Some(e.channel_id),
+        Event::ThreadCreate(e) => Some(e.0.id),
+        Event::ThreadDelete(e) => Some(e.id),
+        Event::ThreadUpdate(e) => Some(e.0.id),
+        Event::TypingStart(e) => Some(e.channel_id),
+        Event::WebhooksUpdate(e) => Some(e.channel_id),
+        _ => None,
+    }
+}
+
+/// Key events are serialized on under `ordering`, `None` if the event can be
+/// processed in any order.
+fn ordering_key(event: &Event, ordering: EventOrdering) -> Option<u64> {
+    // Interactions have to be answered within a few seconds, they can't wait
+    // behind the rest of their guild
+    if let Event::InteractionCreate(_) = event {
+        return None;
+    }
+    // Snowflakes are unique across guilds and channels, so both can share
+    // one key space
+    match ordering {
+        EventOrdering::None => None,
+        EventOrdering::Guild => guild_id(event).map(Id::get),
+        EventOrdering::Channel => channel_id(event)
+            .map(Id::get)
+            .or_else(|| guild_id(event).map(Id::get)),
+    }
+}
+
+/// Hands an event to the plugins enabled in its guild, or only to those in
+/// `only` when it's being retried. Returns the plugins that failed.
+async fn handle_event(
+    event: Event,
+    ctx: Context,
+    plugin_timeout: Duration,
+    only: Option<Vec<String>>,
+) -> Vec<PluginFailure> {
+    let guild_id = match guild_id(&event) {
+        Some(guild_id) => guild_id,
+        None => {
+            // Commands are registered globally, so they can be invoked from DMs
+            if let Event::InteractionCreate(interaction) = &event {
+                if let Err(why) = reply_outside_guild(&interaction.0, &ctx).await {
+                    warn!("Failed to answer interaction outside a guild: {:?}", why);
                 }
             }
+            event!(Level::DEBUG, "Ignoring non-guild event: {:?}", event.kind());
+            return Vec::new();
         }
-        // Guild Based events
-        Event::GuildUpdate(update_event) => {
-            let GuildUpdate(e) = *update_event.clone();
-            let guild_id = e.id;
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
-
-                r1.get_plugins(&ctx, guild_id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (guild update): ({:#?}) in {}",
-                plugins,
-                guild_id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
-            }
-        }
-        Event::GuildCreate(create_event) => {
-            let GuildCreate(e) = *create_event.clone();
-            let guild_id = e.id;
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
+    };
 
-                r1.get_plugins(&ctx, guild_id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (guild create): ({:#?}) in {}",
-                plugins,
-                guild_id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
-            }
-        }
-        Event::GuildDelete(delete_event) => {
-            let GuildDelete { id, .. } = delete_event.clone();
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
+    // Events are processed concurrently, so each one gets its own hub to keep
+    // breadcrumbs and tags from leaking into reports for other events
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("guild_id", guild_id);
+        scope.set_tag("event_type", format!("{:?}", event.kind()));
+    });
+    hub.add_breadcrumb(Breadcrumb {
+        category: Some("gateway".into()),
+        message: Some(format!("Processing {:?} in {}", event.kind(), guild_id)),
+        ..Default::default()
+    });
+
+    dispatch_event(event, ctx, guild_id, plugin_timeout, only)
+        .bind_hub(hub)
+        .await
+}
 
-                r1.get_plugins(&ctx, id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (guild delete): ({:#?}) in {}",
-                plugins,
-                id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
-            }
-        }
-        Event::MemberAdd(add_event) => {
-            let MemberAdd(e) = *add_event.clone();
-            let guild_id = e.guild_id;
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
+async fn dispatch_event(
+    event: Event,
+    ctx: Context,
+    guild_id: Id<GuildMarker>,
+    plugin_timeout: Duration,
+    only: Option<Vec<String>>,
+) -> Vec<PluginFailure> {
+    let kind = EventTypeFlags::from(event.kind());
+    let enabled = {
+        let r1 = ctx.plugin_config.read().await;
+
+        r1.get_plugins(&ctx, guild_id).await
+    };
+    let selected = |plugin: &Arc<Box<dyn Plugin>>| {
+        only.as_ref()
+            .is_none_or(|only| only.iter().any(|name| name == plugin.name()))
+    };
 
-                r1.get_plugins(&ctx, guild_id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (member add): ({:#?}) in {}",
-                plugins,
-                guild_id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
+    let mut failures = Vec::new();
+    if let Event::InteractionCreate(interaction) = &event {
+        let failure = match &interaction.0 {
+            Interaction::ApplicationCommand(command) => {
+                dispatch_command(command.clone(), &enabled, &ctx, plugin_timeout, selected).await
             }
-        }
-        Event::MemberRemove(remove_event) => {
-            let MemberRemove { guild_id, .. } = remove_event.clone();
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
-
-                r1.get_plugins(&ctx, guild_id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (member remove): ({:#?}) in {}",
-                plugins,
-                guild_id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
+            Interaction::MessageComponent(component) => {
+                dispatch_component(component.clone(), &enabled, &ctx, plugin_timeout, selected)
+                    .await
             }
-        }
-        Event::InviteCreate(create_event) => {
-            let guild_id = create_event.guild_id;
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
+            _ => None,
+        };
+        failures.extend(failure);
+    }
 
-                r1.get_plugins(&ctx, guild_id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (invite create): ({:#?}) in {}",
-                plugins,
-                guild_id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
-            }
+    let plugins: Vec<_> = enabled
+        .into_iter()
+        .filter(|p| p.events().contains(kind) && selected(p))
+        .collect();
+    event!(
+        Level::DEBUG,
+        "Got Plugins ({:?}): ({:#?}) in {}",
+        event.kind(),
+        plugins,
+        guild_id
+    );
+
+    // Every plugin gets a chance to run, the ones that failed decide how the
+    // delivery is settled
+    for plugin in plugins.iter() {
+        let run = plugin.on_event(event.clone(), ctx.clone());
+        if let Err(error) = run_plugin(plugin, run, &ctx, plugin_timeout).await {
+            failures.push(PluginFailure {
+                plugin: plugin.name(),
+                error,
+            });
         }
-        Event::InviteDelete(delete_event) => {
-            let guild_id = delete_event.guild_id;
-            let plugins: Vec<_> = {
-                let r1 = plugin_config.read().await;
+    }
 
-                r1.get_plugins(&ctx, guild_id).await
-            };
-            event!(
-                Level::DEBUG,
-                "Got Plugins (invite delete): ({:#?}) in {}",
-                plugins,
-                guild_id
-            );
-
-            for plugin in plugins.iter() {
-                match plugin.on_event(event.clone(), ctx.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "error in plugin ({}): {:#?}",
-                            e,
-                            plugin.name()
-                        );
-                    }
-                };
-            }
+    failures
+}
+
+/// Tells the user an interaction from outside a guild can't be handled, so
+/// it doesn't just fail on their end.
+async fn reply_outside_guild(interaction: &Interaction, ctx: &Context) -> Result<()> {
+    const REPLY: &str = "This only works in a server";
+    match interaction {
+        Interaction::ApplicationCommand(command) => {
+            CommandContext::new(command.clone(), ctx.http.clone())
+                .reply_ephemeral(REPLY)
+                .await
         }
+        Interaction::MessageComponent(component) => {
+            ComponentContext::new(component.clone(), ctx.http.clone())
+                .reply_ephemeral(REPLY)
+                .await
+        }
+        _ => Ok(()),
+    }
+}
 
-        Event::ReactionAdd(e) => {
-            if let Some(guild_id) = e.guild_id {
-                let plugins: Vec<_> = {
-                    let r1 = plugin_config.read().await;
+/// Hands an application command to the plugin that declared it, or tells the
+/// user the plugin is disabled in their guild.
+async fn dispatch_command(
+    command: Box<ApplicationCommand>,
+    enabled: &[Arc<Box<dyn Plugin>>],
+    ctx: &Context,
+    plugin_timeout: Duration,
+    selected: impl Fn(&Arc<Box<dyn Plugin>>) -> bool,
+) -> Option<PluginFailure> {
+    let plugin = ctx
+        .plugin_config
+        .read()
+        .await
+        .command_owner(&command.data.name)
+        // Registered by something other than this worker
+        .filter(|plugin| selected(plugin))?;
+    let command = CommandContext::new(command, ctx.http.clone());
+
+    let result = if enabled.iter().any(|p| Arc::ptr_eq(p, &plugin)) {
+        let run = plugin.on_command(command, ctx.clone());
+        run_plugin(&plugin, run, ctx, plugin_timeout).await
+    } else {
+        command
+            .reply_ephemeral(format!("`{}` is disabled in this server", plugin.name()))
+            .await
+    };
+    result.err().map(|error| PluginFailure {
+        plugin: plugin.name(),
+        error,
+    })
+}
 
-                    r1.get_plugins(&ctx, guild_id).await
-                };
+/// Hands a component interaction to the plugin owning its custom id
+/// namespace, or tells the user the plugin is disabled in their guild.
+async fn dispatch_component(
+    component: Box<MessageComponentInteraction>,
+    enabled: &[Arc<Box<dyn Plugin>>],
+    ctx: &Context,
+    plugin_timeout: Duration,
+    selected: impl Fn(&Arc<Box<dyn Plugin>>) -> bool,
+) -> Option<PluginFailure> {
+    let plugin = ctx
+        .plugin_config
+        .read()
+        .await
+        .component_owner(&component.data.custom_id)
+        // Link buttons and components of other applications
+        .filter(|plugin| selected(plugin))?;
+    let component = ComponentContext::new(component, ctx.http.clone());
+
+    let result = if enabled.iter().any(|p| Arc::ptr_eq(p, &plugin)) {
+        let run = plugin.on_component(component, ctx.clone());
+        run_plugin(&plugin, run, ctx, plugin_timeout).await
+    } else {
+        component
+            .reply_ephemeral(format!("`{}` is disabled in this server", plugin.name()))
+            .await
+    };
+    result.err().map(|error| PluginFailure {
+        plugin: plugin.name(),
+        error,
+    })
+}
+
+/// Runs a single plugin callback, bounded by `plugin_timeout` and with panics
+/// caught, so one misbehaving plugin can't stall or kill the rest.
+///
+/// The plugin runs on its own Sentry hub tagged with its name (and the error
+/// variant if it fails). Panics are already reported by the panic
+/// integration, so they're only logged as a warning here.
+pub(crate) async fn run_plugin(
+    plugin: &Arc<Box<dyn Plugin>>,
+    run: impl Future<Output = Result<()>>,
+    ctx: &Context,
+    plugin_timeout: Duration,
+) -> Result<()> {
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| scope.set_tag("plugin", plugin.name()));
+
+    let metrics = ctx.metrics.clone();
+    let timer = metrics
+        .plugin_event_duration
+        .with_label_values(&[plugin.name()])
+        .start_timer();
+    let run = AssertUnwindSafe(run).catch_unwind();
+    let result = match timeout(plugin_timeout, run).bind_hub(hub.clone()).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(panic)) => Err(Error::PluginPanicked(panic_message(panic.as_ref()))),
+        Err(_) => Err(Error::PluginTimedOut(plugin_timeout)),
+    };
+    timer.observe_duration();
+
+    if let Err(why) = &result {
+        metrics
+            .plugin_event_errors
+            .with_label_values(&[plugin.name(), why.variant()])
+            .inc();
+        hub.configure_scope(|scope| scope.set_tag("error", why.variant()));
+        Hub::run(hub, || {
+            if let Error::PluginPanicked(_) = why {
+                event!(Level::WARN, "plugin ({}) panicked: {}", plugin.name(), why);
+            } else {
                 event!(
-                    Level::DEBUG,
-                    "Got Plugins (reaction add): ({:#?}) in {}",
-                    plugins,
-                    guild_id
+                    Level::ERROR,
+                    "error in plugin ({}): {:#?}",
+                    plugin.name(),
+                    why
                 );
-
-                for plugin in plugins.iter() {
-                    match plugin.on_event(event.clone(), ctx.clone()).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            event!(
-                                Level::ERROR,
-                                "error in plugin ({}): {:#?}",
-                                e,
-                                plugin.name()
-                            );
-                        }
-                    };
-                }
             }
-        }
-        Event::ShardConnected(_) => {
-            event!(Level::DEBUG, "Connected? (this shouldn't be printing)");
-        }
+        });
+    }
+    result
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message_delete(guild_id: Option<&str>) -> Event {
+        Event::MessageDelete(
+            serde_json::from_value(json!({
+                "channel_id": "20",
+                "guild_id": guild_id,
+                "id": "30",
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn guild_id_of_guild_events() {
+        assert_eq!(guild_id(&message_delete(Some("10"))), Some(Id::new(10)));
+
+        let event = Event::ReactionRemoveAll(
+            serde_json::from_value(json!({
+                "channel_id": "20",
+                "guild_id": "10",
+                "message_id": "30",
+            }))
+            .unwrap(),
+        );
+        assert_eq!(guild_id(&event), Some(Id::new(10)));
+    }
+
+    #[test]
+    fn guild_id_of_dm_and_non_guild_events() {
+        assert_eq!(guild_id(&message_delete(None)), None);
+        assert_eq!(guild_id(&Event::GatewayHeartbeatAck), None);
+    }
+
+    #[test]
+    fn parses_dispatch_events() {
+        let payload = br#"{"op":0,"s":1,"t":"MESSAGE_DELETE","d":{"id":"30","channel_id":"20","guild_id":"10"}}"#;
+        let event = parse_event(payload).unwrap();
+        assert!(matches!(event, Event::MessageDelete(_)));
+        assert_eq!(guild_id(&event), Some(Id::new(10)));
+    }
+
+    #[test]
+    fn parses_heartbeat_acks() {
+        let event = parse_event(br#"{"op":11,"d":null}"#).unwrap();
+        assert!(matches!(event, Event::GatewayHeartbeatAck));
+    }
 
-        Event::MemberUpdate(_) => {}
-        n => {
-            event!(Level::DEBUG, "Unknown event: {:#?}", n);
+    #[test]
+    fn rejects_malformed_payloads() {
+        for payload in [
+            &b"\xff\xfe"[..],
+            br#"{"d":{}}"#,
+            br#"{"op":0,"s":1,"t":"MESSAGE_DELETE","d":{"id":"not a snowflake"}}"#,
+            b"not json",
+        ] {
+            assert!(parse_event(payload).is_err(), "{:?}", payload);
         }
     }
 
-    Ok(())
+    #[test]
+    fn channel_id_of_events() {
+        assert_eq!(channel_id(&message_delete(Some("10"))), Some(Id::new(20)));
+        assert_eq!(channel_id(&message_delete(None)), Some(Id::new(20)));
+        assert_eq!(channel_id(&Event::GatewayHeartbeatAck), None);
+    }
 }
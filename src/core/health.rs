@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock as StdRwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// State the readiness check can't probe for itself, reported by the parts
+/// of the worker that own it.
+#[derive(Default)]
+pub struct Health {
+    consuming: AtomicBool,
+    last_sync: StdRwLock<HashMap<&'static str, u64>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set by the event handler while its consumer is receiving deliveries.
+    pub fn set_consuming(&self, consuming: bool) {
+        self.consuming.store(consuming, Ordering::Relaxed);
+    }
+
+    pub fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::Relaxed)
+    }
+
+    /// Records a successful `sync_db` for a plugin.
+    pub fn synced(&self, plugin: &'static str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.last_sync
+            .write()
+            .expect("health lock poisoned")
+            .insert(plugin, now);
+    }
+
+    /// Unix timestamp of a plugin's last successful `sync_db`, if any.
+    pub fn last_sync(&self, plugin: &str) -> Option<u64> {
+        self.last_sync
+            .read()
+            .expect("health lock poisoned")
+            .get(plugin)
+            .copied()
+    }
+}
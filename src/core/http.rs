@@ -0,0 +1,162 @@
+use crate::core::prelude::*;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::TEXT_FORMAT;
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::result::Result as StdResult;
+use tokio::time::{timeout, Duration};
+
+/// How long a single dependency gets to answer a readiness probe.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serves the worker's operational endpoints until shutdown is triggered:
+///
+/// - `/metrics`: Prometheus metrics
+/// - `/livez`: answers as long as the runtime is responsive
+/// - `/readyz`: checks RabbitMQ, the consumer, Mongo and Redis, and reports
+///   when each plugin last synced; 503 when anything is down
+pub async fn serve(ctx: Context, addr: SocketAddr) -> Result<()> {
+    let mut shutdown = ctx.shutdown.clone();
+    let make_service = make_service_fn(move |_| {
+        let ctx = ctx.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| route(req, ctx.clone()))) }
+    });
+
+    event!(Level::INFO, "Serving metrics and health checks on {}", addr);
+    Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.recv().await })
+        .await?;
+    Ok(())
+}
+
+async fn route(
+    req: Request<Body>,
+    ctx: Context,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(&ctx),
+        (&Method::GET, "/livez") => Response::new(Body::from("ok")),
+        (&Method::GET, "/readyz") => readiness(&ctx).await,
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    };
+    Ok(response)
+}
+
+fn metrics(ctx: &Context) -> Response<Body> {
+    // Pool usage is cheap to read, so it's sampled at scrape time rather
+    // than kept up to date on every checkout
+    let status = ctx.redis_pool.status();
+    let redis_pool = &ctx.metrics.redis_pool;
+    redis_pool
+        .with_label_values(&["max"])
+        .set(status.max_size as i64);
+    redis_pool
+        .with_label_values(&["open"])
+        .set(status.size as i64);
+    redis_pool
+        .with_label_values(&["available"])
+        .set(status.available as i64);
+
+    Response::builder()
+        .header(CONTENT_TYPE, TEXT_FORMAT)
+        .body(Body::from(ctx.metrics.encode()))
+        .unwrap()
+}
+
+/// Probes every dependency; the pod is ready only when all of them are up and
+/// it isn't shutting down.
+async fn readiness(ctx: &Context) -> Response<Body> {
+    let rabbit_conn = ctx.rabbit_conn.get();
+    let rabbitmq = if rabbit_conn.status().connected() {
+        Ok(())
+    } else {
+        Err(format!("connection is {:?}", rabbit_conn.status().state()))
+    };
+    let consumer = if ctx.health.is_consuming() {
+        Ok(())
+    } else {
+        Err("not consuming".to_string())
+    };
+    let (mongo, redis) = tokio::join!(check(ping_mongo(ctx)), check(ping_redis(ctx)));
+
+    let checks = [
+        ("rabbitmq", rabbitmq),
+        ("consumer", consumer),
+        ("mongo", mongo),
+        ("redis", redis),
+    ];
+    let shutting_down = ctx.shutdown.is_shutdown();
+    let ready = !shutting_down && checks.iter().all(|(_, result)| result.is_ok());
+
+    let plugins: serde_json::Map<_, _> = ctx
+        .plugin_config
+        .read()
+        .await
+        .plugins
+        .iter()
+        .map(|plugin| {
+            (
+                plugin.name().to_string(),
+                json!({ "last_sync": ctx.health.last_sync(plugin.name()) }),
+            )
+        })
+        .collect();
+    let checks: serde_json::Map<_, _> = checks
+        .iter()
+        .map(|(name, result)| {
+            let check = match result {
+                Ok(()) => json!({ "ok": true }),
+                Err(why) => json!({ "ok": false, "error": why }),
+            };
+            (name.to_string(), check)
+        })
+        .collect();
+    let body = json!({
+        "ready": ready,
+        "shutting_down": shutting_down,
+        "checks": checks,
+        "plugins": plugins,
+    });
+
+    Response::builder()
+        .status(if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        })
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn check(probe: impl std::future::Future<Output = Result<()>>) -> StdResult<(), String> {
+    match timeout(CHECK_TIMEOUT, probe).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(why)) => Err(why.to_string()),
+        Err(_) => Err(format!("timed out after {:?}", CHECK_TIMEOUT)),
+    }
+}
+
+#[cfg(feature = "mongo")]
+async fn ping_mongo(ctx: &Context) -> Result<()> {
+    ctx.db.run_command(doc! {"ping": 1}, None).await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "mongo"))]
+async fn ping_mongo(_ctx: &Context) -> Result<()> {
+    Ok(())
+}
+
+async fn ping_redis(ctx: &Context) -> Result<()> {
+    let mut conn = ctx.redis_pool.get().await?;
+    cmd("PING").query_async::<_, String>(&mut conn).await?;
+    Ok(())
+}
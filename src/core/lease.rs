@@ -0,0 +1,78 @@
+use crate::core::prelude::*;
+use deadpool_redis::Pool as RedisPool;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+
+/// Takes the lease if it's free, or extends it if we already hold it.
+const ACQUIRE_SCRIPT: &str = r"
+local holder = redis.call('GET', KEYS[1])
+if holder and holder ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return 1
+";
+
+/// Deletes the lease, but only if we're the ones holding it.
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+fn lease_key(name: &str) -> String {
+    format!("lease:{}", name)
+}
+
+/// Named, expiring locks in Redis, for work only one worker should do at a
+/// time.
+///
+/// A lease is held until it's released or isn't renewed within its TTL, so
+/// a worker that dies only blocks the work for that long.
+pub struct Leases {
+    pool: Arc<RedisPool>,
+    /// Identifies this worker as the holder of its leases.
+    holder: String,
+}
+
+impl Leases {
+    pub fn new(pool: Arc<RedisPool>) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+        Self {
+            pool,
+            holder: format!("{}:{}:{}", host, std::process::id(), started),
+        }
+    }
+
+    /// Takes the lease `name` for `ttl`, or extends it if this worker already
+    /// holds it. Returns whether this worker holds it now.
+    pub async fn acquire(&self, name: &str, ttl: Duration) -> Result<bool> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("EVAL")
+            .arg(ACQUIRE_SCRIPT)
+            .arg(1)
+            .arg(lease_key(name))
+            .arg(&self.holder)
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    /// Gives up the lease `name` if this worker holds it.
+    pub async fn release(&self, name: &str) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("EVAL")
+            .arg(RELEASE_SCRIPT)
+            .arg(1)
+            .arg(lease_key(name))
+            .arg(&self.holder)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
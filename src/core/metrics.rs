@@ -0,0 +1,177 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+/// Prometheus collectors shared by everything running in the worker.
+pub struct Metrics {
+    registry: Registry,
+    /// Deliveries by event type and what happened to them (`consumed`, then
+    /// one of `acked`, `retried`, `dead_lettered`, `dropped` or `malformed`).
+    pub deliveries: IntCounterVec,
+    pub plugin_event_duration: HistogramVec,
+    pub plugin_event_errors: IntCounterVec,
+    pub plugin_sync_duration: HistogramVec,
+    pub plugin_sync_errors: IntCounterVec,
+    pub queue_depth: IntGauge,
+    pub events_in_flight: IntGauge,
+    /// Redis pool connections by state (`max`, `open`, `available`).
+    pub redis_pool: IntGaugeVec,
+    /// Mongo pool connections by state (`open`, `checked_out`).
+    pub mongo_pool: IntGaugeVec,
+    /// Scheduled jobs waiting to become due, across every worker.
+    pub jobs_due: IntGauge,
+    /// Jobs run by this worker by plugin and outcome (`completed`, `retried`,
+    /// `dead`).
+    pub jobs: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("worker".to_string()), None).expect("valid metrics prefix");
+
+        let deliveries = IntCounterVec::new(
+            Opts::new("deliveries_total", "RabbitMQ deliveries handled"),
+            &["event_type", "status"],
+        )
+        .unwrap();
+        let plugin_event_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "plugin_event_duration_seconds",
+                "Time spent in a plugin's on_event",
+            ),
+            &["plugin"],
+        )
+        .unwrap();
+        let plugin_event_errors = IntCounterVec::new(
+            Opts::new("plugin_event_errors_total", "Failed on_event calls"),
+            &["plugin", "error"],
+        )
+        .unwrap();
+        let plugin_sync_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "plugin_sync_duration_seconds",
+                "Time spent in a plugin's sync_db",
+            ),
+            &["plugin"],
+        )
+        .unwrap();
+        let plugin_sync_errors = IntCounterVec::new(
+            Opts::new("plugin_sync_errors_total", "Failed sync_db calls"),
+            &["plugin", "error"],
+        )
+        .unwrap();
+        let queue_depth = IntGauge::new("queue_depth", "Messages waiting in the queue").unwrap();
+        let events_in_flight =
+            IntGauge::new("events_in_flight", "Events currently being processed").unwrap();
+        let redis_pool = IntGaugeVec::new(
+            Opts::new("redis_pool_connections", "Redis pool connections"),
+            &["state"],
+        )
+        .unwrap();
+        let mongo_pool = IntGaugeVec::new(
+            Opts::new("mongo_pool_connections", "Mongo pool connections"),
+            &["state"],
+        )
+        .unwrap();
+        let jobs_due = IntGauge::new("jobs_due", "Scheduled jobs waiting to run").unwrap();
+        let jobs = IntCounterVec::new(
+            Opts::new("jobs_total", "Scheduled jobs run"),
+            &["plugin", "status"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(deliveries.clone())).unwrap();
+        registry
+            .register(Box::new(plugin_event_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(plugin_event_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(plugin_sync_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(plugin_sync_errors.clone()))
+            .unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry
+            .register(Box::new(events_in_flight.clone()))
+            .unwrap();
+        registry.register(Box::new(redis_pool.clone())).unwrap();
+        registry.register(Box::new(mongo_pool.clone())).unwrap();
+        registry.register(Box::new(jobs_due.clone())).unwrap();
+        registry.register(Box::new(jobs.clone())).unwrap();
+
+        Self {
+            registry,
+            deliveries,
+            plugin_event_duration,
+            plugin_event_errors,
+            plugin_sync_duration,
+            plugin_sync_errors,
+            queue_depth,
+            events_in_flight,
+            redis_pool,
+            mongo_pool,
+            jobs_due,
+            jobs,
+        }
+    }
+
+    /// Counts a delivery for an event type (the gateway name, e.g.
+    /// `MESSAGE_CREATE`).
+    pub fn delivery(&self, event_type: &str, status: &str) {
+        self.deliveries
+            .with_label_values(&[event_type, status])
+            .inc();
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics encode to text");
+        buffer
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Feeds Mongo connection pool events into `Metrics::mongo_pool`.
+#[cfg(feature = "mongo")]
+pub struct MongoPoolMetrics(pub IntGaugeVec);
+
+#[cfg(feature = "mongo")]
+impl mongodb::event::cmap::CmapEventHandler for MongoPoolMetrics {
+    fn handle_connection_created_event(
+        &self,
+        _event: mongodb::event::cmap::ConnectionCreatedEvent,
+    ) {
+        self.0.with_label_values(&["open"]).inc();
+    }
+
+    fn handle_connection_closed_event(&self, _event: mongodb::event::cmap::ConnectionClosedEvent) {
+        self.0.with_label_values(&["open"]).dec();
+    }
+
+    fn handle_connection_checked_out_event(
+        &self,
+        _event: mongodb::event::cmap::ConnectionCheckedOutEvent,
+    ) {
+        self.0.with_label_values(&["checked_out"]).inc();
+    }
+
+    fn handle_connection_checked_in_event(
+        &self,
+        _event: mongodb::event::cmap::ConnectionCheckedInEvent,
+    ) {
+        self.0.with_label_values(&["checked_out"]).dec();
+    }
+}
@@ -1,12 +1,38 @@
+mod delivery;
 mod plugin;
+mod rabbit;
+mod serial;
+mod shutdown;
 
+pub mod backoff;
+pub mod cache;
+pub mod command;
+pub mod component;
 pub mod error;
 pub mod handler;
+pub mod health;
+pub mod http;
+pub mod lease;
+pub mod metrics;
 pub mod prelude;
+pub mod ratelimiter;
+pub mod scheduler;
+pub mod settings;
 
+pub use cache::RedisCache;
+pub use command::CommandContext;
+pub use component::ComponentContext;
 pub use error::Error;
 pub use handler::EventHandler;
-pub use plugin::Plugin;
+pub use health::Health;
+pub use lease::Leases;
+pub use metrics::Metrics;
+pub use plugin::{Plugin, SyncScope};
+pub use rabbit::RabbitConnection;
+pub use ratelimiter::RedisRatelimiter;
+pub use scheduler::{Job, Scheduler};
+pub use settings::SettingsStore;
+pub use shutdown::Shutdown;
 
 use std::result::Result as StdResult;
 pub type Result<T> = StdResult<T, Error>;
@@ -1,7 +1,20 @@
 use crate::core::prelude::*;
+use crate::core::{CommandContext, ComponentContext, Job};
 use crate::Context;
+use tokio::time::Duration;
 use twilight_gateway::Event;
+use twilight_gateway::EventTypeFlags;
 use twilight_gateway::Intents;
+use twilight_model::application::command::Command;
+
+/// Which workers run a plugin's `sync_db`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncScope {
+    /// Every worker, for syncing state it keeps in memory.
+    Worker,
+    /// One worker at a time, the one holding the plugin's sync lease.
+    Cluster,
+}
 
 #[async_trait::async_trait]
 pub trait Plugin: std::fmt::Debug + Send + Sync {
@@ -10,10 +23,90 @@ pub trait Plugin: std::fmt::Debug + Send + Sync {
         Intents::empty()
     }
 
+    /// Gateway events this plugin wants passed to `on_event`. Events outside
+    /// this set are never cloned or dispatched to the plugin.
+    #[inline]
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::empty()
+    }
+
+    /// Whether the plugin runs in guilds that haven't explicitly enabled or
+    /// disabled it.
+    #[inline]
+    fn default_enabled(&self) -> bool {
+        false
+    }
+
+    /// Application commands this plugin handles, registered with Discord at
+    /// startup. Names must be unique across plugins.
+    fn commands(&self) -> Vec<Command> {
+        Vec::new()
+    }
+
+    /// Namespace of the message components (buttons, select menus) this
+    /// plugin handles: components whose custom id starts with it followed by
+    /// `:` are routed to `on_component`. Must be unique across plugins.
+    fn component_namespace(&self) -> Option<&'static str> {
+        None
+    }
+
     async fn on_event(&self, event: Event, context: Context) -> Result<()>;
 
+    /// Called when one of `commands` is invoked in a guild that has the
+    /// plugin enabled.
+    async fn on_command(&self, _command: CommandContext, _context: Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a component in `component_namespace` is used in a guild
+    /// that has the plugin enabled.
+    async fn on_component(&self, _component: ComponentContext, _context: Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a job this plugin scheduled through `Context::scheduler`
+    /// is due. Jobs run at least once: returning an error retries the job
+    /// later, so this should be idempotent.
+    async fn on_job(&self, _job: Job, _context: Context) -> Result<()> {
+        Ok(())
+    }
+
     async fn sync_db(&self, context: &Context) -> Result<()>;
 
+    /// Where `sync_db` runs. Plugins whose sync acts on shared data rather
+    /// than local state should be `Cluster`, so it doesn't run once per pod.
+    fn sync_scope(&self) -> SyncScope {
+        SyncScope::Worker
+    }
+
+    /// How often `sync_db` runs, unless the worker config sets an interval
+    /// for this plugin. `None` uses the worker's `sync_interval`.
+    fn sync_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called once at startup, before any events are dispatched.
+    async fn on_load(&self, _context: &Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once at shutdown, after the final `sync_db`.
+    async fn on_unload(&self, _context: &Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a guild enables the plugin. Only one worker runs this per
+    /// change.
+    async fn on_guild_enable(&self, _guild_id: Id<GuildMarker>, _context: &Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a guild disables the plugin. Only one worker runs this per
+    /// change.
+    async fn on_guild_disable(&self, _guild_id: Id<GuildMarker>, _context: &Context) -> Result<()> {
+        Ok(())
+    }
+
     fn name(&self) -> &'static str;
 
     fn description(&self) -> &'static str;
@@ -2,9 +2,12 @@ pub use deadpool_redis::redis::cmd;
 pub use serde::{Deserialize, Serialize};
 use std::result::Result as StdResult;
 pub use tracing::{event, Level};
-pub use twilight_gateway::Event;
+pub use twilight_gateway::{Event, EventTypeFlags};
+#[cfg(any(feature = "giveaways", feature = "timers"))]
+pub use twilight_model::application::component::{
+    button::ButtonStyle, ActionRow, Button, Component,
+};
 pub use twilight_model::{
-    application::component::{button::ButtonStyle, ActionRow, Button, Component},
     channel::message::AllowedMentions,
     id::{marker::*, Id},
 };
@@ -18,6 +21,13 @@ pub mod colors {
 
 pub use crate::core::error::Error;
 pub type Result<T> = StdResult<T, Error>;
+#[cfg(any(
+    feature = "dank-memer",
+    feature = "giveaways",
+    feature = "math-solving",
+    feature = "timers"
+))]
+pub use crate::core::settings::PluginSettings;
 pub use crate::core::Plugin;
 pub use crate::Context;
 #[cfg(feature = "chrono")]
@@ -25,4 +35,3 @@ pub use chrono::prelude::*;
 #[cfg(feature = "mongo")]
 pub use mongodb::{bson, bson::doc};
 pub use std::sync::Arc;
-pub use tokio::sync::RwLock;
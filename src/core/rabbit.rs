@@ -0,0 +1,55 @@
+use crate::core::backoff;
+use crate::core::Result;
+use lapin::{Connection, ConnectionProperties};
+use std::sync::{Arc, RwLock as StdRwLock};
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+/// RabbitMQ connection that can be replaced after the broker goes away.
+///
+/// Channels and consumers don't survive a reconnect, whoever owns them has to
+/// recreate them on the connection returned by `get`.
+pub struct RabbitConnection {
+    uri: String,
+    conn: StdRwLock<Arc<Connection>>,
+    /// Serializes reconnects so concurrent callers don't each open a
+    /// connection
+    reconnecting: Mutex<()>,
+}
+
+impl RabbitConnection {
+    /// Connects to `uri`, retrying with backoff until the broker is reachable.
+    pub async fn connect(uri: &str) -> Result<Self> {
+        let conn = backoff::retry("connect to RabbitMQ", || Self::open(uri)).await?;
+        Ok(Self {
+            uri: uri.to_string(),
+            conn: StdRwLock::new(Arc::new(conn)),
+            reconnecting: Mutex::new(()),
+        })
+    }
+
+    /// The current connection, which may be broken until `reconnect` is called.
+    pub fn get(&self) -> Arc<Connection> {
+        self.conn.read().expect("rabbit lock poisoned").clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.get().status().connected()
+    }
+
+    /// Opens a new connection if the current one is no longer usable.
+    pub async fn reconnect(&self) -> Result<()> {
+        let _guard = self.reconnecting.lock().await;
+        if self.is_connected() {
+            return Ok(());
+        }
+        let conn = Self::open(&self.uri).await?;
+        event!(Level::INFO, "Reconnected to RabbitMQ");
+        *self.conn.write().expect("rabbit lock poisoned") = Arc::new(conn);
+        Ok(())
+    }
+
+    async fn open(uri: &str) -> Result<Connection> {
+        Ok(Connection::connect(uri, ConnectionProperties::default()).await?)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::core::prelude::*;
+use deadpool_redis::Pool as RedisPool;
+use std::fmt;
+use tokio::time::{sleep, Duration};
+use tracing::warn;
+use twilight_http_ratelimiting::headers::RatelimitHeaders;
+use twilight_http_ratelimiting::ticket::{self, TicketNotifier};
+use twilight_http_ratelimiting::{
+    GetBucketFuture, GetTicketFuture, HasBucketFuture, IsGloballyLockedFuture, Path, Ratelimiter,
+};
+
+/// Set while Discord has us globally ratelimited, expires when the lock lifts.
+const GLOBAL_KEY: &str = "ratelimit:global";
+
+/// Takes a ticket from a bucket, returning how many milliseconds to wait
+/// before trying again, or 0 if the request can go ahead.
+///
+/// Buckets we haven't seen a response for yet don't limit anything.
+const ACQUIRE_SCRIPT: &str = r"
+local global = redis.call('PTTL', KEYS[1])
+if global > 0 then
+    return global
+end
+local remaining = redis.call('HGET', KEYS[2], 'remaining')
+if not remaining then
+    return 0
+end
+if tonumber(remaining) > 0 then
+    redis.call('HINCRBY', KEYS[2], 'remaining', -1)
+    return 0
+end
+local reset = redis.call('PTTL', KEYS[2])
+if reset > 0 then
+    return reset
+end
+redis.call('DEL', KEYS[2])
+return 0
+";
+
+/// Stores the limits from a response's headers. `remaining` never goes back
+/// up within a window, since other workers may have taken tickets after
+/// this response was sent.
+const UPDATE_SCRIPT: &str = r"
+local remaining = tonumber(ARGV[2])
+local current = redis.call('HGET', KEYS[1], 'remaining')
+if current and tonumber(current) < remaining then
+    remaining = tonumber(current)
+end
+redis.call('HSET', KEYS[1], 'limit', ARGV[1], 'remaining', remaining)
+redis.call('PEXPIRE', KEYS[1], ARGV[3])
+";
+
+fn bucket_key(path: &Path) -> String {
+    format!("ratelimit:bucket:{:?}", path)
+}
+
+/// HTTP ratelimiter keeping its buckets in Redis, so every worker sharing
+/// the bot token also shares its limits.
+///
+/// If Redis can't be reached requests are let through rather than stalled,
+/// Discord will answer with a 429 if we actually go over.
+#[derive(Clone)]
+pub struct RedisRatelimiter {
+    pool: Arc<RedisPool>,
+}
+
+impl RedisRatelimiter {
+    pub fn new(pool: Arc<RedisPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Waits until both the global lock and the path's bucket allow another
+    /// request.
+    async fn acquire(&self, key: &str) -> Result<()> {
+        loop {
+            let mut conn = self.pool.get().await?;
+            let wait: u64 = cmd("EVAL")
+                .arg(ACQUIRE_SCRIPT)
+                .arg(2)
+                .arg(GLOBAL_KEY)
+                .arg(key)
+                .query_async(&mut conn)
+                .await?;
+            drop(conn);
+
+            if wait == 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(wait)).await;
+        }
+    }
+
+    async fn update(&self, key: &str, headers: RatelimitHeaders) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        match headers {
+            RatelimitHeaders::GlobalLimited(global) => {
+                warn!("Globally ratelimited for {}s", global.retry_after());
+                cmd("SET")
+                    .arg(GLOBAL_KEY)
+                    .arg(1)
+                    .arg("PX")
+                    .arg(global.retry_after().max(1) * 1000)
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+            }
+            RatelimitHeaders::Present(present) => {
+                cmd("EVAL")
+                    .arg(UPDATE_SCRIPT)
+                    .arg(1)
+                    .arg(key)
+                    .arg(present.limit())
+                    .arg(present.remaining())
+                    .arg(present.reset_after().max(1))
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn run_ticket(self, key: String, notifier: TicketNotifier) {
+        if let Err(why) = self.acquire(&key).await {
+            warn!("Failed to take ratelimit ticket for {}: {:?}", key, why);
+        }
+
+        let headers = match notifier.available() {
+            Some(headers) => headers,
+            // The request was dropped before it got its ticket
+            None => return,
+        };
+        if let Ok(Some(headers)) = headers.await {
+            if let Err(why) = self.update(&key, headers).await {
+                warn!("Failed to update ratelimit bucket {}: {:?}", key, why);
+            }
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("EXISTS").arg(key).query_async(&mut conn).await?)
+    }
+}
+
+impl fmt::Debug for RedisRatelimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisRatelimiter").finish_non_exhaustive()
+    }
+}
+
+impl Ratelimiter for RedisRatelimiter {
+    /// Always `None`, `Bucket` can only be constructed by twilight itself.
+    fn bucket(&self, _path: &Path) -> GetBucketFuture {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn globally_locked(&self) -> IsGloballyLockedFuture {
+        let ratelimiter = self.clone();
+        Box::pin(async move { Ok(ratelimiter.exists(GLOBAL_KEY).await?) })
+    }
+
+    fn has(&self, path: &Path) -> HasBucketFuture {
+        let ratelimiter = self.clone();
+        let key = bucket_key(path);
+        Box::pin(async move { Ok(ratelimiter.exists(&key).await?) })
+    }
+
+    fn ticket(&self, path: Path) -> GetTicketFuture {
+        let (notifier, receiver) = ticket::channel();
+        tokio::spawn(self.clone().run_ticket(bucket_key(&path), notifier));
+        Box::pin(async { Ok(receiver) })
+    }
+}
@@ -0,0 +1,559 @@
+use crate::core::backoff::Backoff;
+use crate::core::handler::run_plugin;
+use crate::core::prelude::*;
+use deadpool_redis::redis::pipe;
+use deadpool_redis::Pool as RedisPool;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, Duration};
+use tracing::{error, warn};
+
+/// Job payloads by key (`{plugin}:{id}`).
+const JOBS_KEY: &str = "scheduler:jobs";
+/// Keys of the jobs waiting to run, scored by when they're due (unix ms).
+const DUE_KEY: &str = "scheduler:due";
+/// Keys of the jobs a worker is running, scored by when its lease runs out.
+const LEASES_KEY: &str = "scheduler:leases";
+/// Jobs that failed `max_attempts` times, kept for inspection.
+const DEAD_KEY: &str = "scheduler:dead";
+
+/// Jobs claimed per poll.
+const CLAIM_BATCH: usize = 50;
+/// How long a job stays leased beyond the time it's allowed to run for.
+const LEASE_MARGIN: Duration = Duration::from_secs(30);
+/// Delay before a job no worker here can run is offered again.
+const UNOWNED_DELAY: Duration = Duration::from_secs(60);
+/// Delay before the first retry of a failed job, doubled for every retry
+/// after it up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+/// Adds a job, or moves it if it's scheduled for a different time. A job
+/// that's running only gets its payload replaced, it's put back in the due
+/// set when the run settles.
+///
+/// KEYS: jobs, due, leases
+/// ARGV: job key, payload, due
+const SCHEDULE_SCRIPT: &str = r"
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+if current and cjson.decode(current).due == tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+if not redis.call('ZSCORE', KEYS[3], ARGV[1]) then
+    redis.call('ZADD', KEYS[2], ARGV[3], ARGV[1])
+end
+return 1
+";
+
+/// Ends a run of a job: `complete` drops it, `retry` stores the new payload
+/// and makes it due again at the given time, `requeue` only does the latter
+/// and `bury` moves the new payload to the dead jobs.
+///
+/// Nothing but the lease is touched if the job was cancelled or rescheduled
+/// while it ran, a rescheduled job is just made due at its new time.
+///
+/// KEYS: jobs, due, leases, dead
+/// ARGV: job key, payload the run was claimed with, action, new payload,
+/// due
+const SETTLE_SCRIPT: &str = r"
+redis.call('ZREM', KEYS[3], ARGV[1])
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+if not current then
+    return 0
+end
+if current ~= ARGV[2] then
+    redis.call('ZADD', KEYS[2], cjson.decode(current).due, ARGV[1])
+    return 0
+end
+if ARGV[3] == 'complete' then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+elseif ARGV[3] == 'retry' then
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[4])
+    redis.call('ZADD', KEYS[2], ARGV[5], ARGV[1])
+elseif ARGV[3] == 'requeue' then
+    redis.call('ZADD', KEYS[2], ARGV[5], ARGV[1])
+elseif ARGV[3] == 'bury' then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+    redis.call('HSET', KEYS[4], ARGV[1], ARGV[4])
+end
+return 1
+";
+
+/// Returns jobs whose lease expired to the due set, then leases the due
+/// ones to the caller. Keys without a payload were cancelled.
+const CLAIM_SCRIPT: &str = r"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[3], '-inf', ARGV[1])
+for _, key in ipairs(expired) do
+    redis.call('ZREM', KEYS[3], key)
+    redis.call('ZADD', KEYS[2], ARGV[1], key)
+end
+local keys = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1], 'LIMIT', 0, ARGV[3])
+local jobs = {}
+for _, key in ipairs(keys) do
+    redis.call('ZREM', KEYS[2], key)
+    local job = redis.call('HGET', KEYS[1], key)
+    if job then
+        redis.call('ZADD', KEYS[3], ARGV[2], key)
+        table.insert(jobs, job)
+    end
+end
+return jobs
+";
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before 1970")
+        .as_millis() as i64
+}
+
+/// A unit of work a plugin asked to run at a given time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    /// Plugin whose `on_job` runs it.
+    pub plugin: String,
+    /// Identifies the job within the plugin, e.g. a giveaway's id.
+    pub id: String,
+    /// When the job is due, in unix milliseconds.
+    pub due: i64,
+    #[serde(default)]
+    pub data: serde_json::Value,
+    /// Failed runs so far.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl Job {
+    fn key(&self) -> String {
+        format!("{}:{}", self.plugin, self.id)
+    }
+}
+
+/// Durable job queue shared by every worker, kept in Redis.
+///
+/// Jobs run at least once: a worker leases the jobs that are due, and the
+/// lease only ends once the plugin's `on_job` returned. Failed jobs are
+/// retried with a backoff, and jobs of a worker that died are picked up by
+/// another one when their lease runs out. Handlers should be idempotent.
+pub struct Scheduler {
+    pool: Arc<RedisPool>,
+    poll_interval: Duration,
+    job_timeout: Duration,
+    max_attempts: u32,
+}
+
+impl Scheduler {
+    pub fn new(
+        pool: Arc<RedisPool>,
+        poll_interval: Duration,
+        job_timeout: Duration,
+        max_attempts: u32,
+    ) -> Self {
+        Self {
+            pool,
+            poll_interval,
+            job_timeout,
+            max_attempts,
+        }
+    }
+
+    /// Schedules a job for `plugin`, due at `due` (unix milliseconds), or
+    /// moves the job with this id if it's due at a different time.
+    ///
+    /// Returns false without changing anything if the job is already
+    /// scheduled for `due`, so it's safe to call repeatedly.
+    pub async fn schedule(
+        &self,
+        plugin: &str,
+        id: &str,
+        due: i64,
+        data: impl Serialize,
+    ) -> Result<bool> {
+        let job = Job {
+            plugin: plugin.to_string(),
+            id: id.to_string(),
+            due,
+            data: serde_json::to_value(data)?,
+            attempts: 0,
+        };
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("EVAL")
+            .arg(SCHEDULE_SCRIPT)
+            .arg(3)
+            .arg(JOBS_KEY)
+            .arg(DUE_KEY)
+            .arg(LEASES_KEY)
+            .arg(job.key())
+            .arg(serde_json::to_string(&job)?)
+            .arg(due)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    /// Drops a job. A run already in progress isn't interrupted.
+    pub async fn cancel(&self, plugin: &str, id: &str) -> Result<()> {
+        let key = format!("{}:{}", plugin, id);
+        let mut conn = self.pool.get().await?;
+        pipe()
+            .atomic()
+            .cmd("HDEL")
+            .arg(JOBS_KEY)
+            .arg(&key)
+            .cmd("ZREM")
+            .arg(DUE_KEY)
+            .arg(&key)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Polls for due jobs and runs them on their plugins until shutdown.
+    pub async fn run(ctx: Context) {
+        let scheduler = ctx.scheduler.clone();
+        let mut shutdown = ctx.shutdown.clone();
+        loop {
+            tokio::select! {
+                _ = sleep(scheduler.poll_interval) => {}
+                _ = shutdown.recv() => return,
+            }
+
+            let jobs = match scheduler.claim().await {
+                Ok(jobs) => jobs,
+                Err(why) => {
+                    error!("Failed to claim scheduled jobs: {:?}", why);
+                    continue;
+                }
+            };
+            for (job, payload) in jobs {
+                let ctx = ctx.clone();
+                ctx.shutdown.clone().spawn(async move {
+                    ctx.scheduler.execute(job, &payload, &ctx).await;
+                });
+            }
+
+            match scheduler.due_count().await {
+                Ok(count) => ctx.metrics.jobs_due.set(count),
+                Err(why) => warn!("Failed to count scheduled jobs: {:?}", why),
+            }
+        }
+    }
+
+    /// Leases the jobs that are due, along with the payload each was claimed
+    /// with.
+    async fn claim(&self) -> Result<Vec<(Job, String)>> {
+        let now = now_ms();
+        let lease_until = now + (self.job_timeout + LEASE_MARGIN).as_millis() as i64;
+        let mut conn = self.pool.get().await?;
+        let jobs: Vec<String> = cmd("EVAL")
+            .arg(CLAIM_SCRIPT)
+            .arg(3)
+            .arg(JOBS_KEY)
+            .arg(DUE_KEY)
+            .arg(LEASES_KEY)
+            .arg(now)
+            .arg(lease_until)
+            .arg(CLAIM_BATCH)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(jobs
+            .into_iter()
+            .filter_map(|payload| match serde_json::from_str(&payload) {
+                Ok(job) => Some((job, payload)),
+                Err(why) => {
+                    error!("Dropping malformed job {}: {:?}", payload, why);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn due_count(&self) -> Result<i64> {
+        let mut conn = self.pool.get().await?;
+        Ok(cmd("ZCARD").arg(DUE_KEY).query_async(&mut conn).await?)
+    }
+
+    async fn execute(&self, job: Job, payload: &str, ctx: &Context) {
+        let plugin = ctx
+            .plugin_config
+            .read()
+            .await
+            .plugins
+            .iter()
+            .find(|p| p.name() == job.plugin)
+            .cloned();
+        let plugin = match plugin {
+            Some(plugin) => plugin,
+            None => {
+                // Disabled on this worker, leave it for one that runs it
+                warn!("No plugin {} for job {}", job.plugin, job.id);
+                if let Err(why) = self.requeue(&job, payload, UNOWNED_DELAY).await {
+                    error!("Failed to requeue job {}: {:?}", job.key(), why);
+                }
+                return;
+            }
+        };
+
+        let run = plugin.on_job(job.clone(), ctx.clone());
+        let result = run_plugin(&plugin, run, ctx, self.job_timeout).await;
+
+        let settled = match result {
+            Ok(()) => self.complete(&job, payload).await.map(|_| "completed"),
+            Err(_) if job.attempts + 1 >= self.max_attempts => {
+                error!(
+                    "Job {} failed {} times, giving up",
+                    job.key(),
+                    job.attempts + 1
+                );
+                self.bury(&job, payload).await.map(|_| "dead")
+            }
+            Err(_) => self.retry(job.clone(), payload).await.map(|_| "retried"),
+        };
+        match settled {
+            Ok(status) => ctx
+                .metrics
+                .jobs
+                .with_label_values(&[&job.plugin, status])
+                .inc(),
+            // The lease runs out and someone runs it again
+            Err(why) => error!("Failed to settle job {}: {:?}", job.key(), why),
+        }
+    }
+
+    async fn complete(&self, job: &Job, payload: &str) -> Result<()> {
+        self.settle(job, payload, "complete", "", 0).await
+    }
+
+    /// Reschedules a failed job, backing off exponentially.
+    async fn retry(&self, mut job: Job, payload: &str) -> Result<()> {
+        let delay = retry_delay(job.attempts);
+        job.attempts += 1;
+        let due = now_ms() + delay.as_millis() as i64;
+        let retried = serde_json::to_string(&job)?;
+        self.settle(&job, payload, "retry", &retried, due).await
+    }
+
+    /// Hands a job back without counting it as a failure.
+    async fn requeue(&self, job: &Job, payload: &str, delay: Duration) -> Result<()> {
+        let due = now_ms() + delay.as_millis() as i64;
+        self.settle(job, payload, "requeue", "", due).await
+    }
+
+    async fn bury(&self, job: &Job, payload: &str) -> Result<()> {
+        let buried = serde_json::to_string(job)?;
+        self.settle(job, payload, "bury", &buried, 0).await
+    }
+
+    async fn settle(
+        &self,
+        job: &Job,
+        payload: &str,
+        action: &str,
+        new_payload: &str,
+        due: i64,
+    ) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        cmd("EVAL")
+            .arg(SETTLE_SCRIPT)
+            .arg(4)
+            .arg(JOBS_KEY)
+            .arg(DUE_KEY)
+            .arg(LEASES_KEY)
+            .arg(DEAD_KEY)
+            .arg(job.key())
+            .arg(payload)
+            .arg(action)
+            .arg(new_payload)
+            .arg(due)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Delay before retrying a job that failed `attempts` times before.
+fn retry_delay(attempts: u32) -> Duration {
+    Backoff::new(RETRY_DELAY, MAX_RETRY_DELAY).delay(attempts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_redis::{Config, Runtime};
+    use std::sync::OnceLock;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    /// The scheduler's keys are fixed, so tests sharing a Redis take turns.
+    static REDIS: OnceLock<Mutex<()>> = OnceLock::new();
+
+    /// A scheduler on an emptied Redis, `REDIS_URL` or database 15 of a local
+    /// one.
+    async fn scheduler() -> (Scheduler, MutexGuard<'static, ()>) {
+        let guard = REDIS.get_or_init(|| Mutex::new(())).lock().await;
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/15".to_string());
+        let pool = Config::from_url(url)
+            .create_pool(Some(Runtime::Tokio1))
+            .unwrap();
+        let mut conn = pool.get().await.unwrap();
+        cmd("DEL")
+            .arg(&[JOBS_KEY, DUE_KEY, LEASES_KEY, DEAD_KEY])
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .unwrap();
+        let scheduler = Scheduler::new(
+            Arc::new(pool),
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+            3,
+        );
+        (scheduler, guard)
+    }
+
+    async fn score(scheduler: &Scheduler, set: &str, key: &str) -> Option<i64> {
+        let mut conn = scheduler.pool.get().await.unwrap();
+        cmd("ZSCORE")
+            .arg(set)
+            .arg(key)
+            .query_async(&mut conn)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn retries_back_off_up_to_the_max() {
+        assert_eq!(retry_delay(0), RETRY_DELAY);
+        assert_eq!(retry_delay(1), RETRY_DELAY * 2);
+        assert_eq!(retry_delay(3), RETRY_DELAY * 8);
+        assert_eq!(retry_delay(20), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn claims_only_due_jobs_once() {
+        let (scheduler, _guard) = scheduler().await;
+        let now = now_ms();
+        assert!(scheduler.schedule("p", "due", now - 1, ()).await.unwrap());
+        assert!(scheduler
+            .schedule("p", "later", now + 60_000, ())
+            .await
+            .unwrap());
+        // Same time again changes nothing
+        assert!(!scheduler.schedule("p", "due", now - 1, ()).await.unwrap());
+
+        let claimed = scheduler.claim().await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].0.id, "due");
+        assert!(score(&scheduler, LEASES_KEY, "p:due").await.is_some());
+        assert!(scheduler.claim().await.unwrap().is_empty());
+
+        let (job, payload) = &claimed[0];
+        scheduler.complete(job, payload).await.unwrap();
+        assert_eq!(score(&scheduler, LEASES_KEY, "p:due").await, None);
+        assert!(scheduler.claim().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn rescheduling_moves_the_job() {
+        let (scheduler, _guard) = scheduler().await;
+        let later = now_ms() + 60_000;
+        scheduler.schedule("p", "job", later, ()).await.unwrap();
+        assert!(scheduler.schedule("p", "job", later + 1, ()).await.unwrap());
+        assert_eq!(score(&scheduler, DUE_KEY, "p:job").await, Some(later + 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn rescheduling_while_running_survives_the_run() {
+        let (scheduler, _guard) = scheduler().await;
+        scheduler
+            .schedule("p", "job", now_ms() - 1, ())
+            .await
+            .unwrap();
+        let (job, payload) = scheduler.claim().await.unwrap().remove(0);
+
+        let later = now_ms() + 60_000;
+        scheduler.schedule("p", "job", later, ()).await.unwrap();
+        assert_eq!(score(&scheduler, DUE_KEY, "p:job").await, None);
+        scheduler.complete(&job, &payload).await.unwrap();
+        assert_eq!(score(&scheduler, DUE_KEY, "p:job").await, Some(later));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn failed_jobs_are_retried_with_backoff() {
+        let (scheduler, _guard) = scheduler().await;
+        scheduler
+            .schedule("p", "job", now_ms() - 1, ())
+            .await
+            .unwrap();
+        let (job, payload) = scheduler.claim().await.unwrap().remove(0);
+
+        let before = now_ms();
+        scheduler.retry(job, &payload).await.unwrap();
+        let due = score(&scheduler, DUE_KEY, "p:job").await.unwrap();
+        assert!(due >= before + RETRY_DELAY.as_millis() as i64);
+        assert_eq!(score(&scheduler, LEASES_KEY, "p:job").await, None);
+
+        let mut conn = scheduler.pool.get().await.unwrap();
+        let stored: String = cmd("HGET")
+            .arg(JOBS_KEY)
+            .arg("p:job")
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(serde_json::from_str::<Job>(&stored).unwrap().attempts, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn buried_jobs_move_to_the_dead_set() {
+        let (scheduler, _guard) = scheduler().await;
+        scheduler
+            .schedule("p", "job", now_ms() - 1, ())
+            .await
+            .unwrap();
+        let (job, payload) = scheduler.claim().await.unwrap().remove(0);
+        scheduler.bury(&job, &payload).await.unwrap();
+
+        let mut conn = scheduler.pool.get().await.unwrap();
+        let (live, dead): (bool, bool) = deadpool_redis::redis::pipe()
+            .cmd("HEXISTS")
+            .arg(JOBS_KEY)
+            .arg("p:job")
+            .cmd("HEXISTS")
+            .arg(DEAD_KEY)
+            .arg("p:job")
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(!live);
+        assert!(dead);
+        assert_eq!(score(&scheduler, LEASES_KEY, "p:job").await, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn expired_leases_are_claimed_again() {
+        let (scheduler, _guard) = scheduler().await;
+        scheduler
+            .schedule("p", "job", now_ms() - 1, ())
+            .await
+            .unwrap();
+        assert_eq!(scheduler.claim().await.unwrap().len(), 1);
+        assert!(scheduler.claim().await.unwrap().is_empty());
+
+        // The worker running it died and its lease ran out
+        let mut conn = scheduler.pool.get().await.unwrap();
+        cmd("ZADD")
+            .arg(LEASES_KEY)
+            .arg(now_ms() - 1)
+            .arg("p:job")
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .unwrap();
+        let claimed = scheduler.claim().await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].0.id, "job");
+    }
+}
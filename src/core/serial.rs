@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::oneshot;
+
+/// The last future queued for a key.
+struct Tail {
+    seq: u64,
+    /// Completes once that future has finished
+    done: oneshot::Receiver<()>,
+    /// Futures queued for the key that haven't finished yet
+    queued: usize,
+}
+
+type Tails = StdMutex<HashMap<u64, Tail>>;
+
+/// Runs futures sharing a key one after another, in the order they were
+/// queued, while futures with different keys run concurrently.
+pub struct SerialExecutor {
+    tails: Arc<Tails>,
+    next_seq: AtomicU64,
+    /// Futures queued for one key at most before more aren't ordered
+    max_queued: usize,
+}
+
+impl SerialExecutor {
+    pub fn new(max_queued: usize) -> Self {
+        Self {
+            tails: Arc::default(),
+            next_seq: AtomicU64::new(0),
+            max_queued,
+        }
+    }
+
+    /// Wraps `future` so it only starts once every future previously queued
+    /// with the same key has finished. The order is fixed when this is
+    /// called, not when the returned future is first polled.
+    ///
+    /// Futures without a key aren't ordered at all, nor are futures for a key
+    /// that already has `max_queued` waiting.
+    pub fn queue<F>(&self, key: Option<u64>, future: F) -> impl Future<Output = F::Output>
+    where
+        F: Future,
+    {
+        let slot = key.and_then(|key| self.take_slot(key));
+
+        async move {
+            let (previous, _guard) = match slot {
+                Some(slot) => slot,
+                None => return future.await,
+            };
+            if let Some(previous) = previous {
+                // Errors just mean the previous future was dropped, which is
+                // as finished as it's going to get
+                let _ = previous.await;
+            }
+            future.await
+        }
+    }
+
+    /// Makes a future the new tail of `key`, returning the completion signal
+    /// of the one before it. `None` if the key is full.
+    fn take_slot(&self, key: u64) -> Option<(Option<oneshot::Receiver<()>>, SlotGuard)> {
+        let mut tails = self.tails.lock().expect("serial executor poisoned");
+        let queued = tails.get(&key).map_or(0, |tail| tail.queued);
+        if queued >= self.max_queued {
+            return None;
+        }
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (done, tail) = oneshot::channel();
+        let previous = tails.insert(
+            key,
+            Tail {
+                seq,
+                done: tail,
+                queued: queued + 1,
+            },
+        );
+        let guard = SlotGuard {
+            tails: self.tails.clone(),
+            key,
+            seq,
+            _done: done,
+        };
+        Some((previous.map(|previous| previous.done), guard))
+    }
+}
+
+/// Releases the next future for a key (by dropping `done`) and forgets the
+/// key if nothing was queued behind us, even if the future panics or is
+/// dropped before it ran.
+struct SlotGuard {
+    tails: Arc<Tails>,
+    key: u64,
+    seq: u64,
+    _done: oneshot::Sender<()>,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let mut tails = self.tails.lock().expect("serial executor poisoned");
+        if let Some(tail) = tails.get_mut(&self.key) {
+            if tail.seq == self.seq {
+                tails.remove(&self.key);
+            } else {
+                tail.queued -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn same_key_runs_in_queue_order() {
+        let executor = SerialExecutor::new(10);
+        let order = Arc::new(StdMutex::new(Vec::new()));
+        let tasks: Vec<_> = (0..3u64)
+            .map(|i| {
+                let order = order.clone();
+                // Earlier futures sleep longer, so they'd finish last if run
+                // concurrently
+                tokio::spawn(executor.queue(Some(1), async move {
+                    sleep(Duration::from_millis(30 - i * 10)).await;
+                    order.lock().unwrap().push(i);
+                }))
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn different_keys_run_concurrently() {
+        let executor = SerialExecutor::new(10);
+        let (tx, rx) = oneshot::channel();
+        // The first future can only finish once the second has started
+        let first = tokio::spawn(executor.queue(Some(1), async move {
+            rx.await.unwrap();
+        }));
+        let second = tokio::spawn(executor.queue(Some(2), async move {
+            tx.send(()).unwrap();
+        }));
+
+        timeout(Duration::from_secs(1), async {
+            first.await.unwrap();
+            second.await.unwrap();
+        })
+        .await
+        .expect("futures with different keys waited on each other");
+    }
+
+    #[tokio::test]
+    async fn dropped_future_releases_the_next() {
+        let executor = SerialExecutor::new(10);
+        let first = executor.queue(Some(1), async {});
+        let second = executor.queue(Some(1), async { 2 });
+        drop(first);
+
+        let result = timeout(Duration::from_secs(1), second).await;
+        assert_eq!(result.ok(), Some(2));
+        assert!(executor.tails.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn full_key_stops_ordering() {
+        let executor = SerialExecutor::new(1);
+        let (tx, rx) = oneshot::channel::<()>();
+        // Holds the key's only slot until the overflow has run
+        let first = tokio::spawn(executor.queue(Some(1), async move {
+            let _ = rx.await;
+        }));
+        let overflow = executor.queue(Some(1), async move {
+            tx.send(()).unwrap();
+        });
+
+        timeout(Duration::from_secs(1), async {
+            overflow.await;
+            first.await.unwrap();
+        })
+        .await
+        .expect("future past the limit waited on its key");
+        assert!(executor.tails.lock().unwrap().is_empty());
+    }
+}
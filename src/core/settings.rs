@@ -0,0 +1,200 @@
+use crate::core::prelude::*;
+use serde::de::DeserializeOwned;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::RwLock as StdRwLock;
+use tokio::time::{Duration, Instant};
+use tracing::error;
+
+/// Per-guild settings declared by a plugin.
+///
+/// Each guild gets one document per plugin in the `plugin_settings`
+/// collection, shaped `{guild_id, plugin, settings}`. Fields missing from
+/// `settings` fall back to `Default`, so implementors should be
+/// `#[serde(default)]`.
+pub trait PluginSettings:
+    Serialize + DeserializeOwned + Default + Clone + Send + Sync + 'static
+{
+    /// Name the settings are stored under, usually the plugin's name.
+    const PLUGIN: &'static str;
+
+    /// Problems with these settings, checked whenever they're loaded so a
+    /// guild can't configure something the plugin would fail on.
+    fn problems(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// How long the fallback settings are cached for after a failed load, so a
+/// Mongo outage or a malformed document doesn't pin them for `cache_ttl`.
+const ERROR_CACHE_TTL: Duration = Duration::from_secs(5);
+
+type CacheKey = (Id<GuildMarker>, &'static str);
+/// When the entry expires and the settings it holds.
+type CacheEntry = (Instant, Arc<dyn Any + Send + Sync>);
+
+/// Loads plugin settings and caches them per guild.
+pub struct SettingsStore {
+    cache: StdRwLock<HashMap<CacheKey, CacheEntry>>,
+    cache_ttl: Duration,
+    /// Settings from the worker config by plugin, applied underneath each
+    /// guild's own.
+    defaults: HashMap<String, serde_json::Value>,
+}
+
+impl SettingsStore {
+    pub fn new(cache_ttl: Duration, defaults: HashMap<String, serde_json::Value>) -> Self {
+        Self {
+            cache: StdRwLock::new(HashMap::new()),
+            cache_ttl,
+            defaults,
+        }
+    }
+
+    /// Returns a guild's settings for a plugin, or the configured defaults if
+    /// it hasn't set any (or they can't be loaded).
+    pub async fn get<S: PluginSettings>(&self, ctx: &Context, guild_id: Id<GuildMarker>) -> Arc<S> {
+        let key = (guild_id, S::PLUGIN);
+        {
+            let cache = self.cache.read().expect("settings cache poisoned");
+            if let Some((expires, settings)) = cache.get(&key) {
+                if Instant::now() < *expires {
+                    if let Ok(settings) = settings.clone().downcast::<S>() {
+                        return settings;
+                    }
+                }
+            }
+        }
+
+        let (settings, ttl) = match self.load::<S>(ctx, guild_id).await {
+            Ok(settings) => (Arc::new(settings), self.cache_ttl),
+            Err(why) => {
+                error!(
+                    "Failed to load {} settings for guild {}: {:?}",
+                    S::PLUGIN,
+                    guild_id,
+                    why
+                );
+                let settings = self.configured().unwrap_or_default();
+                (Arc::new(settings), ERROR_CACHE_TTL.min(self.cache_ttl))
+            }
+        };
+        self.cache
+            .write()
+            .expect("settings cache poisoned")
+            .insert(key, (Instant::now() + ttl, settings.clone()));
+        settings
+    }
+
+    /// Drops the cached settings of a single guild, or of every guild when
+    /// `guild_id` is `None`.
+    pub fn invalidate(&self, guild_id: Option<Id<GuildMarker>>) {
+        let mut cache = self.cache.write().expect("settings cache poisoned");
+        match guild_id {
+            Some(guild_id) => cache.retain(|(id, _), _| *id != guild_id),
+            None => cache.clear(),
+        }
+    }
+
+    /// The settings from the worker config, or `Default` without any.
+    fn configured<S: PluginSettings>(&self) -> Result<S> {
+        match self.defaults.get(S::PLUGIN) {
+            Some(settings) => checked(serde_json::from_value(settings.clone())?),
+            None => Ok(S::default()),
+        }
+    }
+
+    #[cfg(feature = "mongo")]
+    async fn load<S: PluginSettings>(&self, ctx: &Context, guild_id: Id<GuildMarker>) -> Result<S> {
+        let document = ctx
+            .db
+            .collection::<bson::Document>("plugin_settings")
+            .find_one(
+                doc! {"guild_id": guild_id.get().to_string(), "plugin": S::PLUGIN},
+                None,
+            )
+            .await?;
+
+        let settings = match document
+            .as_ref()
+            .and_then(|d| d.get_document("settings").ok())
+        {
+            Some(settings) => settings,
+            None => return self.configured(),
+        };
+
+        // Fields the guild didn't set come from the config before `Default`
+        let mut merged = match self.defaults.get(S::PLUGIN) {
+            Some(defaults) => bson::to_document(defaults)?,
+            None => bson::Document::new(),
+        };
+        merged.extend(settings.clone());
+        checked(bson::from_document(merged)?)
+    }
+
+    #[cfg(not(feature = "mongo"))]
+    async fn load<S: PluginSettings>(
+        &self,
+        _ctx: &Context,
+        _guild_id: Id<GuildMarker>,
+    ) -> Result<S> {
+        self.configured()
+    }
+}
+
+/// Problems with the settings the worker config gives `S`, found by
+/// deserializing them the way `SettingsStore` will.
+#[cfg(any(
+    feature = "dank-memer",
+    feature = "giveaways",
+    feature = "math-solving",
+    feature = "timers"
+))]
+pub fn configured_problems<S: PluginSettings>(
+    configured: &HashMap<String, serde_json::Value>,
+) -> Vec<String> {
+    let settings = match configured.get(S::PLUGIN) {
+        Some(settings) => settings,
+        None => return Vec::new(),
+    };
+    match serde_json::from_value::<S>(settings.clone()) {
+        Ok(settings) => settings
+            .problems()
+            .into_iter()
+            .map(|problem| format!("`plugins.{}.settings`: {}", S::PLUGIN, problem))
+            .collect(),
+        Err(why) => vec![format!(
+            "`plugins.{}.settings` doesn't match the plugin's settings: {}",
+            S::PLUGIN,
+            why
+        )],
+    }
+}
+
+/// Problems with an embed text setting, which Discord requires to be
+/// non-empty and at most `max` characters long.
+#[cfg(any(feature = "giveaways", feature = "timers"))]
+pub fn embed_text_problems(key: &str, value: &str, max: usize) -> Vec<String> {
+    if value.trim().is_empty() {
+        vec![format!("`{}` must not be empty", key)]
+    } else if value.chars().count() > max {
+        vec![format!("`{}` must be at most {} characters", key, max)]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Passes settings through if they have no problems.
+fn checked<S: PluginSettings>(settings: S) -> Result<S> {
+    let problems = settings.problems();
+    if problems.is_empty() {
+        Ok(settings)
+    } else {
+        Err(Error::InvalidConfig(
+            problems
+                .into_iter()
+                .map(|problem| format!("{}: {}", S::PLUGIN, problem))
+                .collect(),
+        ))
+    }
+}
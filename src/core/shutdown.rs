@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+struct Inner {
+    trigger: watch::Sender<bool>,
+    tasks: AtomicUsize,
+    idle: Notify,
+}
+
+/// Process-wide shutdown signal, plus tracking for the tasks that have to
+/// finish (or clean up after themselves) before the worker exits.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+    notify: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, notify) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                trigger,
+                tasks: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+            notify,
+        }
+    }
+
+    /// Tells every listener that the worker is going down.
+    pub fn trigger(&self) {
+        let _ = self.inner.trigger.send(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.notify.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn recv(&mut self) {
+        while !*self.notify.borrow() {
+            if self.notify.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Spawns a task that `wait` will block on.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        inner.tasks.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            let _guard = TaskGuard(inner);
+            future.await
+        })
+    }
+
+    /// Number of tracked tasks still running.
+    pub fn pending(&self) -> usize {
+        self.inner.tasks.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once every task started through `spawn` has finished.
+    pub async fn wait(&self) {
+        loop {
+            let idle = self.inner.idle.notified();
+            if self.pending() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decrements the task count even if the tracked future panics.
+struct TaskGuard(Arc<Inner>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.0.tasks.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
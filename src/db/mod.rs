@@ -1,2 +1,3 @@
+#[cfg(feature = "mongo")]
 pub use mongodb::{options::ClientOptions as MongoClientOptions, Client as MongoClient};
 pub mod models;
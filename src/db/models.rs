@@ -1,20 +1,26 @@
 use crate::core::prelude::*;
-#[cfg(feature = "mongo")]
+#[cfg(any(feature = "giveaways", feature = "timers"))]
 use bson::oid::ObjectId;
-#[cfg(feature = "mongo")]
+#[cfg(any(
+    feature = "dank-memer",
+    feature = "giveaways",
+    feature = "invite-counting",
+    feature = "timers",
+    feature = "utility"
+))]
 use bson::DateTime;
 
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "giveaways")]
 use std::collections::HashMap;
 
+#[cfg(any(feature = "giveaways", feature = "timers"))]
 use tokio::time::Duration as TokioDuration;
-#[cfg(feature = "dank-memer")]
+#[cfg(any(feature = "dank-memer", feature = "invite-counting"))]
 use twilight_model::datetime::Timestamp;
 #[cfg(feature = "invite-counting")]
 use twilight_model::{
-    datetime::Timestamp,
-    invite::{Invite, InviteChannel, InviteGuild, InviteStageInstance, TargetType},
+    invite::{Invite, InviteChannel, InviteGuild, TargetType},
     user::User,
 };
 
@@ -72,6 +78,15 @@ pub struct Giveaway {
     // pub requirements: Option<HashMap<String, Vec<String>>>,
     pub data: HashMap<String, String>,
     pub winners: usize,
+
+    /// Set together with `winner_ids` once the winners were drawn.
+    #[serde(default)]
+    pub ended: bool,
+    /// The drawn winners, announced again as is if ending is retried.
+    #[serde(default)]
+    pub winner_ids: Vec<String>,
+    #[serde(default)]
+    pub progress: EndProgress,
 }
 
 #[cfg(feature = "giveaways")]
@@ -108,6 +123,22 @@ impl Giveaway {
     }
 }
 
+/// How far ending a giveaway or timer got. Ending is retried until it
+/// succeeds, so every step is recorded once done and skipped afterwards.
+#[cfg(any(feature = "giveaways", feature = "timers"))]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct EndProgress {
+    /// The message shows the ended embed.
+    #[serde(default)]
+    pub message_updated: bool,
+    /// The winners or end message were announced.
+    #[serde(default)]
+    pub announced: bool,
+    /// Reminder pings sent so far, one message per chunk of users.
+    #[serde(default)]
+    pub ping_messages: Vec<String>,
+}
+
 #[cfg(feature = "timers")]
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct Timer {
@@ -125,6 +156,9 @@ pub struct Timer {
     pub title: String,
     pub icon_url: String,
     pub end_message: String,
+
+    #[serde(default)]
+    pub progress: EndProgress,
 }
 
 #[cfg(feature = "timers")]
@@ -165,10 +199,29 @@ impl Timer {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, Eq, PartialEq)]
 pub struct GuildPluginConfig {
     pub id: String,
+    /// Plugins the guild has explicitly opted into.
     pub plugins: Vec<String>,
+    /// Plugins the guild has explicitly opted out of.
+    #[serde(default)]
+    pub disabled: Vec<String>,
+}
+
+impl GuildPluginConfig {
+    /// Explicit opt-outs win over opt-ins, anything not mentioned falls back
+    /// to the plugin's default.
+    pub fn is_enabled(&self, plugin: &dyn Plugin) -> bool {
+        let name = plugin.name();
+        if self.disabled.iter().any(|p| p == name) {
+            false
+        } else if self.plugins.iter().any(|p| p == name) {
+            true
+        } else {
+            plugin.default_enabled()
+        }
+    }
 }
 
 #[cfg(feature = "invite-counting")]
@@ -208,8 +261,6 @@ pub struct MongoInvite {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_uses: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub stage_instance: Option<InviteStageInstance>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub target_type: Option<TargetType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target_user: Option<User>,
@@ -233,7 +284,6 @@ impl From<Invite> for MongoInvite {
             inviter: invite.inviter,
             max_age: invite.max_age,
             max_uses: invite.max_uses,
-            stage_instance: invite.stage_instance,
             target_type: invite.target_type,
             target_user: invite.target_user,
             temporary: invite.temporary,
@@ -288,11 +338,72 @@ pub struct JoinStorage {
     pub timestamp: DateTime,
 }
 
-#[cfg(feature = "invite-counting")]
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-pub struct LeaveStorage {
-    pub doctype: String,
-    pub guild_id: String,
-    pub user_id: String,
-    pub timestamp: DateTime,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestPlugin {
+        default_enabled: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for TestPlugin {
+        fn default_enabled(&self) -> bool {
+            self.default_enabled
+        }
+
+        async fn on_event(&self, _event: Event, _context: Context) -> Result<()> {
+            Ok(())
+        }
+
+        async fn sync_db(&self, _context: &Context) -> Result<()> {
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn description(&self) -> &'static str {
+            "Test plugin"
+        }
+    }
+
+    fn config(plugins: &[&str], disabled: &[&str]) -> GuildPluginConfig {
+        GuildPluginConfig {
+            id: "1".to_string(),
+            plugins: plugins.iter().map(|p| p.to_string()).collect(),
+            disabled: disabled.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn unmentioned_plugins_use_their_default() {
+        let on = TestPlugin {
+            default_enabled: true,
+        };
+        let off = TestPlugin {
+            default_enabled: false,
+        };
+        assert!(config(&[], &[]).is_enabled(&on));
+        assert!(!config(&["other"], &[]).is_enabled(&off));
+    }
+
+    #[test]
+    fn opt_ins_enable_plugins_off_by_default() {
+        let plugin = TestPlugin {
+            default_enabled: false,
+        };
+        assert!(config(&["test"], &[]).is_enabled(&plugin));
+    }
+
+    #[test]
+    fn opt_outs_win_over_defaults_and_opt_ins() {
+        let plugin = TestPlugin {
+            default_enabled: true,
+        };
+        assert!(!config(&[], &["test"]).is_enabled(&plugin));
+        assert!(!config(&["test"], &["test"]).is_enabled(&plugin));
+    }
 }
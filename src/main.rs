@@ -1,6 +1,19 @@
-#[macro_use]
+#[cfg_attr(
+    any(
+        feature = "dank-memer",
+        feature = "date-transformer",
+        feature = "giveaways",
+        feature = "invite-counting",
+        feature = "math-solving",
+        feature = "message-counting",
+        feature = "server-indexer",
+        feature = "timers",
+        feature = "utility"
+    ),
+    macro_use
+)]
 extern crate async_trait;
-#[cfg(feature = "dates")]
+#[cfg(feature = "date-transformer")]
 extern crate date_time_parser;
 extern crate deadpool_redis;
 extern crate dotenv;
@@ -9,6 +22,8 @@ extern crate meval;
 extern crate tracing;
 use crate::core::prelude::*;
 use std::sync::Arc;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 
 mod context;
 mod core;
@@ -22,14 +37,15 @@ pub use context::Context;
 #[tokio::main]
 async fn main() -> Result<()> {
     // let subscriber = tracing_subscriber::fmt().with_target(false).finish();
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .compact()
-        .finish();
+    // Errors are sent to sentry as events, warnings and info as breadcrumbs
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer().compact())
+        .with(sentry::integrations::tracing::layer());
     tracing::subscriber::set_global_default(subscriber)
         .expect("Unable to set global default subscriber");
 
-    let config = model::WorkerConfig::from_env()?;
+    let config = model::WorkerConfig::load()?;
 
     let _guard = sentry::init((
         config.sentry_dsn_url.clone(),
@@ -61,8 +77,21 @@ async fn main() -> Result<()> {
         Box::new(plugins::server_indexer::ServerIndexer()),
         #[cfg(feature = "math-solving")]
         Box::new(plugins::math_solving::MathSolving::default()),
+        #[cfg(feature = "utility")]
+        Box::new(plugins::utility::Utility::default()),
     ];
-    let plugins: Arc<Vec<_>> = Arc::new(plugins.into_iter().map(|m| Arc::new(m)).collect());
+    for name in config.plugins.keys() {
+        if !plugins.iter().any(|p| p.name() == name.as_str()) {
+            tracing::warn!("Config has options for unknown plugin {}", name);
+        }
+    }
+    let plugins: Arc<Vec<_>> = Arc::new(
+        plugins
+            .into_iter()
+            .filter(|p| config.plugin_enabled(p.name()))
+            .map(Arc::new)
+            .collect(),
+    );
 
     let mut worker = worker::Worker::new(config, plugins).await;
 
@@ -2,5 +2,5 @@ mod worker_config;
 
 mod plugin_config;
 
-pub use plugin_config::PluginConfig;
-pub use worker_config::WorkerConfig;
+pub use plugin_config::{active_plugins_key, PluginConfig, PLUGIN_UPDATES_CHANNEL};
+pub use worker_config::{EventOrdering, WorkerConfig};
@@ -1,45 +1,463 @@
+use crate::core::component;
 use crate::core::prelude::*;
-use std::sync::Arc;
+use crate::db::models::GuildPluginConfig;
+use deadpool_redis::redis::pipe;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock as StdRwLock};
+use tokio::time::{Duration, Instant};
 use tracing::error;
+use twilight_model::application::command::Command;
+use twilight_model::gateway::event::EventType;
+
+/// Redis channel the dashboard publishes to after changing a guild's plugins
+/// or their settings.
+/// The payload is the guild id, or `*` to drop every cached guild.
+pub const PLUGIN_UPDATES_CHANNEL: &str = "plugins:updates";
+
+/// Guilds whose plugin config is kept in the local cache at most.
+const MAX_CACHED_GUILDS: usize = 10_000;
+
+/// How long the plugin defaults are cached for a guild whose config couldn't
+/// be loaded, so a Mongo outage doesn't pin them for `cache_ttl`.
+const ERROR_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Redis set of the plugins a guild has explicitly opted into.
+fn enabled_plugins_key(guild_id: Id<GuildMarker>) -> String {
+    format!("plugins:{}", guild_id.get())
+}
+
+/// Redis set of the plugins a guild has explicitly opted out of.
+fn disabled_plugins_key(guild_id: Id<GuildMarker>) -> String {
+    format!("plugins:{}:disabled", guild_id.get())
+}
+
+/// Redis set of the plugins whose `on_guild_enable` has run for a guild.
+pub fn active_plugins_key(guild_id: Id<GuildMarker>) -> String {
+    format!("plugins:{}:active", guild_id.get())
+}
+
+/// Set once a guild's active plugins were first recorded.
+fn seeded_key(guild_id: Id<GuildMarker>) -> String {
+    format!("plugins:{}:seeded", guild_id.get())
+}
+
+/// Records the plugins enabled in a guild as active, unless that was done
+/// before. Returns whether it seeded the set.
+const SEED_ACTIVE_SCRIPT: &str = r"
+if not redis.call('SET', KEYS[2], 1, 'NX') then
+    return 0
+end
+if #ARGV > 0 then
+    redis.call('SADD', KEYS[1], unpack(ARGV))
+end
+return 1
+";
+
+/// Adds the first `ARGV[1]` plugins after it to a guild's active set and
+/// removes the rest. Returns the plugins actually added and removed.
+const CLAIM_CHANGES_SCRIPT: &str = r"
+local enabled = tonumber(ARGV[1])
+local added, removed = {}, {}
+for i = 2, #ARGV do
+    if i <= enabled + 1 then
+        if redis.call('SADD', KEYS[1], ARGV[i]) == 1 then
+            table.insert(added, ARGV[i])
+        end
+    elseif redis.call('SREM', KEYS[1], ARGV[i]) == 1 then
+        table.insert(removed, ARGV[i])
+    end
+end
+return {added, removed}
+";
+
+/// When a cached config expires, the config, and whether it's the plugin
+/// defaults standing in for a config that couldn't be loaded.
+type CacheEntry = (Instant, GuildPluginConfig, bool);
+
+/// Plugins that changed state in a guild since its last update.
+#[derive(Default)]
+pub struct PluginChanges {
+    pub enabled: Vec<Arc<Box<dyn Plugin>>>,
+    pub disabled: Vec<Arc<Box<dyn Plugin>>>,
+}
 
 pub struct PluginConfig {
     pub plugins: Arc<Vec<Arc<Box<dyn Plugin>>>>,
+    /// Union of every registered plugin's event subscriptions.
+    pub events: EventTypeFlags,
+    /// Application commands by name, with the plugin that handles them.
+    pub commands: HashMap<String, (Command, Arc<Box<dyn Plugin>>)>,
+    /// Plugins by the component namespace they handle.
+    components: HashMap<&'static str, Arc<Box<dyn Plugin>>>,
+    cache: StdRwLock<HashMap<Id<GuildMarker>, CacheEntry>>,
+    cache_ttl: Duration,
 }
 
 impl PluginConfig {
-    pub fn new(plugins: Arc<Vec<Arc<Box<dyn Plugin>>>>) -> Self {
-        PluginConfig { plugins }
+    pub fn new(plugins: Arc<Vec<Arc<Box<dyn Plugin>>>>, cache_ttl: Duration) -> Self {
+        let mut events = plugins
+            .iter()
+            .fold(EventTypeFlags::empty(), |acc, p| acc | p.events());
+
+        let mut commands: HashMap<String, (Command, Arc<Box<dyn Plugin>>)> = HashMap::new();
+        for plugin in plugins.iter() {
+            for command in plugin.commands() {
+                if let Some((_, owner)) = commands.get(&command.name) {
+                    panic!(
+                        "Command {} is declared by both {} and {}",
+                        command.name,
+                        owner.name(),
+                        plugin.name()
+                    );
+                }
+                commands.insert(command.name.clone(), (command, plugin.clone()));
+            }
+        }
+
+        let mut components = HashMap::new();
+        for plugin in plugins.iter() {
+            if let Some(namespace) = plugin.component_namespace() {
+                if let Some(owner) = components.insert(namespace, plugin.clone()) {
+                    panic!(
+                        "Component namespace {} is claimed by both {} and {}",
+                        namespace,
+                        owner.name(),
+                        plugin.name()
+                    );
+                }
+            }
+        }
+
+        if !commands.is_empty() || !components.is_empty() {
+            events |= EventTypeFlags::INTERACTION_CREATE;
+        }
+
+        PluginConfig {
+            plugins,
+            events,
+            commands,
+            components,
+            cache: StdRwLock::new(HashMap::new()),
+            cache_ttl,
+        }
+    }
+
+    /// The plugin handling an application command.
+    pub fn command_owner(&self, name: &str) -> Option<Arc<Box<dyn Plugin>>> {
+        self.commands.get(name).map(|(_, plugin)| plugin.clone())
+    }
+
+    /// The plugin handling a message component, based on the namespace its
+    /// custom id starts with.
+    pub fn component_owner(&self, custom_id: &str) -> Option<Arc<Box<dyn Plugin>>> {
+        self.components
+            .get(component::namespace(custom_id))
+            .cloned()
+    }
+
+    /// Whether any registered plugin subscribes to events of this type.
+    pub fn is_subscribed(&self, kind: EventType) -> bool {
+        self.events.contains(EventTypeFlags::from(kind))
     }
 
+    /// Returns the registered plugins enabled in a guild, each at most once.
     pub async fn get_plugins(
         &self,
         ctx: &Context,
         guild_id: Id<GuildMarker>,
     ) -> Vec<Arc<Box<dyn Plugin>>> {
-        let mut result: Vec<_> = Vec::new();
-        let mut conn = ctx.redis_pool.get().await.unwrap();
-        let plugins: Vec<String> = cmd("SMEMBERS")
-            .arg(&[format!("plugins:{}", guild_id.get())])
+        let guild_config = self.guild_config(ctx, guild_id).await;
+
+        self.plugins
+            .iter()
+            .filter(|p| guild_config.is_enabled(p.as_ref().as_ref()))
+            .cloned()
+            .collect()
+    }
+
+    /// Drops the cached config of a single guild, or of every guild when
+    /// `guild_id` is `None`.
+    pub fn invalidate(&self, guild_id: Option<Id<GuildMarker>>) {
+        let mut cache = self.cache.write().expect("plugin cache poisoned");
+        match guild_id {
+            Some(guild_id) => {
+                cache.remove(&guild_id);
+            }
+            None => cache.clear(),
+        }
+    }
+
+    /// Compares a guild's current plugin config with the plugins it was last
+    /// seen running, claiming each difference in `plugins:{guild}:active`
+    /// with a single script. Runs on every update published for the guild,
+    /// and periodically for the guilds in the local cache to catch updates no
+    /// worker received.
+    ///
+    /// Every worker receives the same update, the set membership check makes
+    /// sure only one of them gets to run the hook for a given change. Nothing
+    /// is claimed while the guild's config can't be loaded, the defaults
+    /// standing in for it would disable every plugin it opted into.
+    pub async fn claim_changes(
+        &self,
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<PluginChanges> {
+        let (guild_config, fallback) = self.lookup(ctx, guild_id).await;
+        if fallback {
+            return Ok(PluginChanges::default());
+        }
+
+        let (enabled, disabled): (Vec<_>, Vec<_>) = self
+            .plugins
+            .iter()
+            .partition(|p| guild_config.is_enabled(p.as_ref().as_ref()));
+        let mut conn = ctx.redis_pool.get().await?;
+        let (added, removed): (Vec<String>, Vec<String>) = cmd("EVAL")
+            .arg(CLAIM_CHANGES_SCRIPT)
+            .arg(1)
+            .arg(active_plugins_key(guild_id))
+            .arg(enabled.len())
+            .arg(enabled.iter().map(|p| p.name()).collect::<Vec<_>>())
+            .arg(disabled.iter().map(|p| p.name()).collect::<Vec<_>>())
+            .query_async(&mut conn)
+            .await?;
+
+        let claimed = |names: Vec<String>| {
+            self.plugins
+                .iter()
+                .filter(|p| names.iter().any(|name| name == p.name()))
+                .cloned()
+                .collect()
+        };
+        Ok(PluginChanges {
+            enabled: claimed(added),
+            disabled: claimed(removed),
+        })
+    }
+
+    /// Returns a guild's plugin config, served from the local cache while it
+    /// is younger than the configured TTL.
+    ///
+    /// Misses load the explicit opt-ins (`plugins:{guild}`) and opt-outs
+    /// (`plugins:{guild}:disabled`) from Redis. Guilds Redis knows nothing
+    /// about are read from the `plugins` collection in Mongo, the source of
+    /// truth, and copied into Redis. When Redis can't be reached a stale
+    /// cached entry is kept alive, and only guilds we've never seen fall back
+    /// to Mongo. If that fails too the plugin defaults stand in, cached for
+    /// `ERROR_CACHE_TTL` only.
+    pub async fn guild_config(
+        &self,
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+    ) -> GuildPluginConfig {
+        self.lookup(ctx, guild_id).await.0
+    }
+
+    /// `guild_config`, along with whether it's a fallback to the plugin
+    /// defaults.
+    async fn lookup(&self, ctx: &Context, guild_id: Id<GuildMarker>) -> (GuildPluginConfig, bool) {
+        let stale = {
+            let cache = self.cache.read().expect("plugin cache poisoned");
+            match cache.get(&guild_id) {
+                Some((expires, config, fallback)) if Instant::now() < *expires => {
+                    return (config.clone(), *fallback);
+                }
+                Some((_, config, fallback)) => Some((config.clone(), *fallback)),
+                None => None,
+            }
+        };
+
+        let loaded = match Self::redis_guild_config(ctx, guild_id).await {
+            Ok(Some(config)) => Ok(config),
+            Ok(None) => Self::load_guild_config(ctx, guild_id).await,
+            Err(why) => {
+                error!(
+                    "Failed to get plugins for guild {} from redis: {:?}",
+                    guild_id, why
+                );
+                // Left stale, so the next lookup tries Redis again
+                if let Some(stale) = stale {
+                    return stale;
+                }
+                Self::load_guild_config(ctx, guild_id).await
+            }
+        };
+
+        let (config, fallback) = match loaded {
+            Ok(config) => (config, false),
+            Err(why) => {
+                error!(
+                    "Failed to get plugins for guild {} from mongo: {:?}",
+                    guild_id, why
+                );
+                let config = GuildPluginConfig {
+                    id: guild_id.get().to_string(),
+                    ..Default::default()
+                };
+                (config, true)
+            }
+        };
+
+        // Seeding from the defaults would record the wrong plugins as active
+        if !fallback {
+            if let Err(why) = self.seed_active(ctx, guild_id, &config).await {
+                error!(
+                    "Failed to seed active plugins for guild {}: {:?}",
+                    guild_id, why
+                );
+            }
+        }
+        self.cache_config(guild_id, config.clone(), fallback);
+        (config, fallback)
+    }
+
+    /// Guilds with a config in the local cache, the ones this worker has
+    /// recently handled events for.
+    pub fn cached_guilds(&self) -> Vec<Id<GuildMarker>> {
+        self.cache
+            .read()
+            .expect("plugin cache poisoned")
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// The first time any worker sees a guild, records the plugins it runs
+    /// as active without running their hooks, so `claim_changes` only reacts
+    /// to later changes. Otherwise every default-enabled plugin would look
+    /// newly enabled on the guild's first update.
+    async fn seed_active(
+        &self,
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+        config: &GuildPluginConfig,
+    ) -> Result<()> {
+        let enabled: Vec<_> = self
+            .plugins
+            .iter()
+            .filter(|p| config.is_enabled(p.as_ref().as_ref()))
+            .map(|p| p.name())
+            .collect();
+        let mut conn = ctx.redis_pool.get().await?;
+        cmd("EVAL")
+            .arg(SEED_ACTIVE_SCRIPT)
+            .arg(2)
+            .arg(active_plugins_key(guild_id))
+            .arg(seeded_key(guild_id))
+            .arg(enabled)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Caches a guild's config, a fallback only for `ERROR_CACHE_TTL`, making
+    /// room by dropping expired entries (or an arbitrary one if none expired)
+    /// once `MAX_CACHED_GUILDS` is reached.
+    fn cache_config(&self, guild_id: Id<GuildMarker>, config: GuildPluginConfig, fallback: bool) {
+        let ttl = if fallback {
+            ERROR_CACHE_TTL.min(self.cache_ttl)
+        } else {
+            self.cache_ttl
+        };
+        let mut cache = self.cache.write().expect("plugin cache poisoned");
+        if cache.len() >= MAX_CACHED_GUILDS && !cache.contains_key(&guild_id) {
+            let now = Instant::now();
+            cache.retain(|_, (expires, _, _)| now < *expires);
+            if cache.len() >= MAX_CACHED_GUILDS {
+                if let Some(evicted) = cache.keys().next().copied() {
+                    cache.remove(&evicted);
+                }
+            }
+        }
+        cache.insert(guild_id, (Instant::now() + ttl, config, fallback));
+    }
+
+    /// Reads a guild's config from Redis, `None` if Redis has neither set
+    /// for it.
+    async fn redis_guild_config(
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<Option<GuildPluginConfig>> {
+        let mut conn = ctx.redis_pool.get().await?;
+        let (plugins, disabled): (Vec<String>, Vec<String>) = pipe()
+            .cmd("SMEMBERS")
+            .arg(enabled_plugins_key(guild_id))
+            .cmd("SMEMBERS")
+            .arg(disabled_plugins_key(guild_id))
             .query_async(&mut conn)
-            .await
-            .unwrap_or_else(|_| {
-                error!("Failed to get plugins for guild {}", guild_id);
-                Vec::new()
-            });
-        {
-            for plugin_name in plugins {
-                if let Some(plugin) = self
-                    .plugins
-                    .iter()
-                    .find(|p| p.name().to_string() == plugin_name)
-                {
-                    result.push(plugin.clone());
+            .await?;
+        if plugins.is_empty() && disabled.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(GuildPluginConfig {
+            id: guild_id.get().to_string(),
+            plugins,
+            disabled,
+        }))
+    }
+
+    /// Reads a guild's config from Mongo and stores it in Redis, with the
+    /// plugin defaults if the guild has none.
+    async fn load_guild_config(
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<GuildPluginConfig> {
+        match Self::mongo_guild_config(ctx, guild_id).await? {
+            Some(config) => {
+                if let Err(why) = Self::store_guild_config(ctx, guild_id, &config).await {
+                    error!(
+                        "Failed to copy plugins for guild {} to redis: {:?}",
+                        guild_id, why
+                    );
                 }
+                Ok(config)
             }
+            None => Ok(GuildPluginConfig {
+                id: guild_id.get().to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    async fn store_guild_config(
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+        config: &GuildPluginConfig,
+    ) -> Result<()> {
+        let mut pipe = pipe();
+        if !config.plugins.is_empty() {
+            pipe.cmd("SADD")
+                .arg(enabled_plugins_key(guild_id))
+                .arg(&config.plugins);
         }
-        for plugin in self.plugins.iter() {
-            result.push(plugin.clone());
+        if !config.disabled.is_empty() {
+            pipe.cmd("SADD")
+                .arg(disabled_plugins_key(guild_id))
+                .arg(&config.disabled);
         }
-        result
+        let mut conn = ctx.redis_pool.get().await?;
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "mongo")]
+    async fn mongo_guild_config(
+        ctx: &Context,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<Option<GuildPluginConfig>> {
+        Ok(ctx
+            .db
+            .collection::<GuildPluginConfig>("plugins")
+            .find_one(doc! {"id": guild_id.get().to_string()}, None)
+            .await?)
+    }
+
+    #[cfg(not(feature = "mongo"))]
+    async fn mongo_guild_config(
+        _ctx: &Context,
+        _guild_id: Id<GuildMarker>,
+    ) -> Result<Option<GuildPluginConfig>> {
+        Ok(None)
     }
 }
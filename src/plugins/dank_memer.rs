@@ -14,16 +14,10 @@ async fn get_id_from_name(
 ) -> Result<Option<Id<UserMarker>>> {
     Ok(
         if let Some(receiver_id_val) = dank.cache.get(receiver_name) {
-            Some(receiver_id_val.value().clone())
-        } else if let Some(r) = ctx
-            .cache
-            .iter()
-            .users()
-            .filter(|u| u.name.eq(receiver_name))
-            .next()
-        {
-            dank.cache.insert(receiver_name.to_string(), *r.key());
-            Some(*r.key())
+            Some(*receiver_id_val.value())
+        } else if let Some(id) = ctx.cache.user_by_name(receiver_name).await? {
+            dank.cache.insert(receiver_name.to_string(), id);
+            Some(id)
         } else if let Some(id) = ctx
             .http
             .channel_messages(message.channel_id)
@@ -49,6 +43,26 @@ async fn get_id_from_name(
     )
 }
 
+/// Per-guild settings for `DankMemer`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DankMemerSettings {
+    /// The Dank Memer bot whose transfers and trades are tracked.
+    pub bot_id: Id<UserMarker>,
+}
+
+impl Default for DankMemerSettings {
+    fn default() -> Self {
+        Self {
+            bot_id: Id::new(270904126974590976),
+        }
+    }
+}
+
+impl PluginSettings for DankMemerSettings {
+    const PLUGIN: &'static str = "dank_memer";
+}
+
 #[derive(Clone, Debug)]
 pub struct DankMemer {
     pub amount_expr: Regex,
@@ -66,13 +80,22 @@ impl Plugin for DankMemer {
         "Tracking dank memer data"
     }
 
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::MESSAGE_CREATE
+    }
+
     async fn sync_db(&self, _ctx: &Context) -> Result<()> {
         Ok(())
     }
 
     async fn on_event(&self, event: Event, ctx: Context) -> Result<()> {
         if let Event::MessageCreate(message) = event {
-            if message.author.id.get() != 270904126974590976
+            let guild_id = match message.guild_id {
+                Some(guild_id) => guild_id,
+                None => return Ok(()),
+            };
+            let settings = ctx.settings.get::<DankMemerSettings>(&ctx, guild_id).await;
+            if message.author.id != settings.bot_id
                 || !message.content.is_empty()
                 || message.embeds.is_empty()
                 || message.reference.is_none()
@@ -149,16 +172,15 @@ impl Plugin for DankMemer {
 
             if let Some(sender_id) = ctx
                 .cache
-                .iter()
-                .messages()
-                .find(|msg| msg.id() == message.reference.as_ref().unwrap().message_id.unwrap())
-                .map(|msg| msg.author())
+                .message(message.reference.as_ref().unwrap().message_id.unwrap())
+                .await?
+                .map(|msg| msg.author.id)
             {
                 let receiver_text = embed.fields[2].name.to_string();
                 let receiver_name = &receiver_text[0..receiver_text.len() - 9].to_string();
                 let receiver_id: Id<UserMarker>;
                 if let Ok(Some(result)) =
-                    get_id_from_name(&self, &receiver_name, &ctx, &message).await
+                    get_id_from_name(self, receiver_name, &ctx, &message).await
                 {
                     receiver_id = result;
                 } else {
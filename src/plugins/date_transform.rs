@@ -1,6 +1,6 @@
 use crate::core::prelude::*;
 use chrono::NaiveDateTime;
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use date_time_parser::{DateParser, TimeParser};
 use regex::Regex;
 
@@ -19,6 +19,10 @@ impl Plugin for DateTransformer {
         "Transforms dates to a more readable format"
     }
 
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::MESSAGE_CREATE
+    }
+
     async fn sync_db(&self, _ctx: &Context) -> Result<()> {
         Ok(())
     }
@@ -34,12 +38,15 @@ impl Plugin for DateTransformer {
                 return Ok(());
             }
             let mut content = message.content.clone();
-            let src = NaiveDateTime::from_timestamp(message.timestamp.as_secs(), 0);
+            let src = match Utc.timestamp_opt(message.timestamp.as_secs(), 0).single() {
+                Some(src) => src.naive_utc(),
+                None => return Ok(()),
+            };
             for caps in self.regex_expr.captures_iter(&message.content) {
                 let mut relative = false;
 
-                let time: Option<NaiveDateTime> =
-                    DateParser::parse(caps.get(1).unwrap().as_str()).map_or_else(
+                let time: Option<NaiveDateTime> = DateParser::parse(caps.get(1).unwrap().as_str())
+                    .map_or_else(
                         || {
                             relative = true;
                             TimeParser::parse(caps.get(1).unwrap().as_str()).map(|time| {
@@ -51,9 +58,8 @@ impl Plugin for DateTransformer {
                         },
                         |date| Some(NaiveDateTime::new(date, src.time())),
                     );
-                if time.is_some() {
-                    let time = time.unwrap();
-                    let time = DateTime::<Utc>::from_utc(time, Utc);
+                if let Some(time) = time {
+                    let time = Utc.from_utc_datetime(&time);
                     content = content.replacen(
                         caps.get(0).unwrap().as_str(),
                         &format!(
@@ -63,7 +69,6 @@ impl Plugin for DateTransformer {
                         ),
                         1,
                     );
-                } else {
                 }
             }
             if content != message.content {
@@ -1,15 +1,48 @@
 use crate::core::prelude::*;
-use crate::core::Plugin;
+use crate::core::settings::embed_text_problems;
+use crate::core::{ComponentContext, Job, Plugin, SyncScope};
 use crate::db::models::Giveaway;
-use chrono::{Duration as ChronoDuration, Utc};
 use futures::stream::TryStreamExt;
 use rand::seq::SliceRandom;
-use tokio::time::sleep;
-use tracing::error;
-use tracing::info;
-use twilight_embed_builder::EmbedBuilder;
+use tracing::{info, warn};
+use twilight_util::builder::embed::EmbedBuilder;
+use twilight_validate::embed::{FIELD_VALUE_LENGTH, TITLE_LENGTH};
 
-#[derive(Clone, Debug)]
+/// Per-guild settings for `Giveaways`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GiveawaySettings {
+    /// Title of the giveaway embed once it has ended.
+    pub ended_title: String,
+    /// Shown in place of the winners when nobody entered.
+    pub no_winners: String,
+}
+
+impl Default for GiveawaySettings {
+    fn default() -> Self {
+        Self {
+            ended_title: "Giveaway Ended".to_string(),
+            no_winners: "No one won".to_string(),
+        }
+    }
+}
+
+impl PluginSettings for GiveawaySettings {
+    const PLUGIN: &'static str = "giveaways";
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = embed_text_problems("ended_title", &self.ended_title, TITLE_LENGTH);
+        // Leaves the rest of the description for the giveaway's content
+        problems.extend(embed_text_problems(
+            "no_winners",
+            &self.no_winners,
+            FIELD_VALUE_LENGTH,
+        ));
+        problems
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Giveaways {}
 
 #[async_trait]
@@ -22,157 +55,263 @@ impl Plugin for Giveaways {
         "Schedules giveaways"
     }
 
+    fn component_namespace(&self) -> Option<&'static str> {
+        Some("giveaways")
+    }
+
     async fn on_event(&self, _event: Event, _ctx: Context) -> Result<()> {
         Ok(())
     }
 
-    async fn sync_db(&self, ctx: &Context) -> Result<()> {
-        let giveaway_coll = Arc::new(ctx.db.collection::<Giveaway>("giveaways"));
-        let timestamp = Utc::now() + ChronoDuration::seconds(60);
-        let timestamp: bson::DateTime = timestamp.into();
+    /// `giveaways:enter` adds the user to the entrants of the giveaway the
+    /// button is attached to.
+    async fn on_component(&self, component: ComponentContext, ctx: Context) -> Result<()> {
+        let user_id = match (component.id(), component.author_id()) {
+            ("enter", Some(user_id)) => user_id,
+            _ => return component.ack().await,
+        };
+
+        let giveaway = ctx
+            .db
+            .collection::<Giveaway>("giveaways")
+            .find_one(
+                doc! {"message_id": component.message_id().get().to_string()},
+                None,
+            )
+            .await?;
+        let giveaway = match giveaway {
+            Some(giveaway) if !giveaway.get_duration_remaining().is_zero() => giveaway,
+            _ => return component.reply_ephemeral("This giveaway has ended").await,
+        };
 
+        let mut conn = ctx.redis_pool.get().await?;
+        let entered: bool = cmd("SADD")
+            .arg(giveaway.get_store_key())
+            .arg(user_id.get())
+            .query_async(&mut conn)
+            .await?;
+        if entered {
+            component
+                .reply_ephemeral(format!("You entered the giveaway for `{}`", giveaway.prize))
+                .await
+        } else {
+            component
+                .reply_ephemeral("You've already entered this giveaway")
+                .await
+        }
+    }
+
+    fn sync_scope(&self) -> SyncScope {
+        SyncScope::Cluster
+    }
+
+    /// Schedules an ending job for every giveaway that isn't scheduled for
+    /// its current `end` yet, which includes giveaways whose end was moved.
+    async fn sync_db(&self, ctx: &Context) -> Result<()> {
+        let giveaway_coll = ctx.db.collection::<Giveaway>("giveaways");
         let mut giveaway_cursor = giveaway_coll
-            .find(doc! {"active":true, "end":{"$lte":timestamp}}, None)
-            .await
-            .expect("Failed to find giveaways");
+            .find(
+                doc! {"active": true, "$expr": {"$ne": ["$scheduled_end", "$end"]}},
+                None,
+            )
+            .await?;
 
-        let mut results = Vec::new();
         while let Some(giveaway) = giveaway_cursor.try_next().await? {
-            results.push(giveaway._id);
-            let ctx = ctx.clone();
-            // Editing the messages
-
-            let gaw_coll = Arc::clone(&giveaway_coll);
-            tokio::spawn(async move {
-                let (http, mut conn) = {
-                    let http = ctx.http.clone();
-                    let conn = ctx.redis_pool.get().await.unwrap();
-                    (http, conn)
-                };
-                info!("Remaining: {:#?}", giveaway.get_duration_remaining());
-                sleep(giveaway.get_duration_remaining()).await;
-                info!("Ending...");
-
-                let users: Vec<Id<UserMarker>> = cmd("smembers")
-                    .arg(&[giveaway.get_store_key()])
-                    .query_async(&mut conn)
-                    .await
-                    .unwrap_or_else(|_| {
-                        error!("Failed to get users for giveaway {}", giveaway._id);
-                        Vec::new()
-                    })
-                    .into_iter()
-                    .map(|user: String| Id::new(user.parse().unwrap()))
-                    .collect();
-
-                // dump new users into mongo
-                gaw_coll
-                    .find_one_and_update(
-                        doc! {"_id": giveaway._id},
-                        doc! {"$addToSet": { "users": {"$each":  users.iter().map(|u| u.get().to_string()).collect::<Vec<String>>()  } } },
-                        None,
-                    )
-                    .await
-                    .ok();
-                let winners = if !users.is_empty() {
-                    if users.len() > giveaway.winners {
-                        users
-                            .choose_multiple(&mut rand::thread_rng(), giveaway.winners)
-                            .cloned()
-                            .collect()
-                    } else {
-                        users
-                    }
-                } else {
-                    Vec::new()
-                };
-
-                let mut description = format!("{}\n\n", giveaway.get_content());
-
-                let winner_str: String;
-                if !winners.is_empty() {
-                    let _: () = cmd("expire")
-                        .arg(&[giveaway.get_store_key(), "604800".to_string()])
-                        .query_async(&mut conn)
-                        .await
-                        .expect("Redis cmd failed"); // clearly the command worked
-
-                    winner_str = winners
-                        .iter()
-                        .map(|user| format!("<@{}>", user.get()))
-                        .reduce(|acc, user| format!("{}, {}", acc, user))
-                        .unwrap();
-                    description += &format!("Winners: {}", winner_str);
-                } else {
-                    winner_str = "Nobody".to_string();
-                    description += "No one won";
-                };
-
-                let embed = EmbedBuilder::new()
-                    .title("Giveaway Ended")
-                    .description(description)
-                    .build()
-                    .expect("could not construct embed for giveaway");
-
-                if let Err(why) = http
-                    .update_message(giveaway.get_channel_id(), giveaway.get_message_id())
-                    .embeds(Some(&[dbg!(embed)]))
-                    .expect("Could not construct update embed for giveaway")
-                    .components(Some(&[]))
-                    .expect("Could not construct update components for giveaway")
-                    .exec()
-                    .await
-                {
-                    event!(Level::ERROR, "Failed to update giveaway message: {}", why);
-                } else {
-                    info!("Successfully updated giveaway message");
-                    http.create_message(giveaway.get_channel_id())
-                        .content(&format!(
-                            "{} has won the giveaway for `{}`",
-                            &winner_str, &giveaway.prize
-                        ))
-                        .expect("Could not create content for end message")
-                        .components(&[Component::ActionRow(ActionRow {
-                            components: vec![Component::Button(Button {
-                                style: ButtonStyle::Link,
-                                url: Some(format!(
-                                    "https://discord.com/channels/{}/{}/{}",
-                                    giveaway.get_guild_id(),
-                                    giveaway.get_channel_id(),
-                                    giveaway.get_message_id()
-                                )),
-                                label: Some("Jump".to_string()),
-                                custom_id: None,
-                                disabled: false,
-                                emoji: None,
-                            })],
-                        })])
-                        .expect("Could not construct components for end message")
-                        .allowed_mentions(Some(
-                            &AllowedMentions::builder().user_ids(winners).build(),
-                        ))
-                        .exec()
-                        .await
-                        .ok();
-                }
-            });
-        }
-        if !results.is_empty() {
+            ctx.scheduler
+                .schedule(
+                    self.name(),
+                    &giveaway._id.to_hex(),
+                    giveaway.end.timestamp_millis(),
+                    (),
+                )
+                .await?;
+            // Only if `end` didn't move again meanwhile
             giveaway_coll
-                .update_many(
-                    doc! {"_id":{ "$in":results }},
-                    doc! {"$set":doc! {"active":false}},
+                .update_one(
+                    doc! {"_id": giveaway._id, "end": giveaway.end},
+                    doc! {"$set": {"scheduled_end": giveaway.end}},
                     None,
                 )
-                .await
-                .expect("Failed to update giveaways");
+                .await?;
         }
         Ok(())
     }
+
+    /// Ends the giveaway whose id the job carries, unless a previous run
+    /// already did or its end was moved later.
+    async fn on_job(&self, job: Job, ctx: Context) -> Result<()> {
+        let id = bson::oid::ObjectId::parse_str(&job.id)
+            .map_err(|_| Error::InvalidPayload(format!("giveaway id {}", job.id)))?;
+        let giveaway_coll = ctx.db.collection::<Giveaway>("giveaways");
+        let giveaway = match giveaway_coll
+            .find_one(doc! {"_id": id, "active": true}, None)
+            .await?
+        {
+            // The job for the new end takes care of it
+            Some(giveaway) if !giveaway.get_duration_remaining().is_zero() => return Ok(()),
+            Some(giveaway) => giveaway,
+            None => return Ok(()),
+        };
+
+        end_giveaway(giveaway, &ctx).await?;
+        giveaway_coll
+            .update_one(doc! {"_id": id}, doc! {"$set": {"active": false}}, None)
+            .await?;
+        Ok(())
+    }
 }
 
-impl Default for Giveaways {
-    fn default() -> Self {
-        Giveaways {}
+/// Draws the winners, then announces them in the giveaway's channel.
+///
+/// Winners are stored before anything is announced and each announcement
+/// is recorded once sent, so a retried ending picks up where the previous
+/// one stopped and announces the same winners.
+async fn end_giveaway(mut giveaway: Giveaway, ctx: &Context) -> Result<()> {
+    info!("Ending giveaway {}", giveaway._id);
+    let http = &ctx.http;
+    let giveaway_coll = ctx.db.collection::<Giveaway>("giveaways");
+
+    if !giveaway.ended {
+        giveaway = draw_winners(giveaway, ctx).await?;
     }
+    let winners: Vec<Id<UserMarker>> = giveaway
+        .winner_ids
+        .iter()
+        .filter_map(|user| user.parse().ok().and_then(Id::new_checked))
+        .collect();
+
+    let settings = ctx
+        .settings
+        .get::<GiveawaySettings>(ctx, giveaway.get_guild_id())
+        .await;
+    let mut description = format!("{}\n\n", giveaway.get_content());
+
+    let winner_str = if !winners.is_empty() {
+        let winner_str = winners
+            .iter()
+            .map(|user| format!("<@{}>", user.get()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        description += &format!("Winners: {}", winner_str);
+        winner_str
+    } else {
+        description += &settings.no_winners;
+        "Nobody".to_string()
+    };
+
+    if !giveaway.progress.message_updated {
+        let embed = EmbedBuilder::new()
+            .title(settings.ended_title.as_str())
+            .description(description)
+            .validate()
+            .map_err(Error::EmbedFailed)?
+            .build();
+
+        http.update_message(giveaway.get_channel_id(), giveaway.get_message_id())
+            .embeds(Some(&[embed]))?
+            .components(Some(&[]))?
+            .exec()
+            .await?;
+        giveaway_coll
+            .update_one(
+                doc! {"_id": giveaway._id},
+                doc! {"$set": {"progress.message_updated": true}},
+                None,
+            )
+            .await?;
+        info!("Successfully updated giveaway message");
+    }
+
+    if !giveaway.progress.announced {
+        http.create_message(giveaway.get_channel_id())
+            .content(&format!(
+                "{} has won the giveaway for `{}`",
+                &winner_str, &giveaway.prize
+            ))?
+            .components(&[Component::ActionRow(ActionRow {
+                components: vec![Component::Button(Button {
+                    style: ButtonStyle::Link,
+                    url: Some(format!(
+                        "https://discord.com/channels/{}/{}/{}",
+                        giveaway.get_guild_id(),
+                        giveaway.get_channel_id(),
+                        giveaway.get_message_id()
+                    )),
+                    label: Some("Jump".to_string()),
+                    custom_id: None,
+                    disabled: false,
+                    emoji: None,
+                })],
+            })])?
+            .allowed_mentions(Some(&AllowedMentions::builder().user_ids(winners).build()))
+            .exec()
+            .await?;
+        giveaway_coll
+            .update_one(
+                doc! {"_id": giveaway._id},
+                doc! {"$set": {"progress.announced": true}},
+                None,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Draws the winners from everyone who entered and stores them along with
+/// the entrants. Returns the giveaway as stored, which has the winners of
+/// whichever run drew first if two raced.
+async fn draw_winners(giveaway: Giveaway, ctx: &Context) -> Result<Giveaway> {
+    let giveaway_coll = ctx.db.collection::<Giveaway>("giveaways");
+    let mut conn = ctx.redis_pool.get().await?;
+
+    let users: Vec<Id<UserMarker>> = cmd("smembers")
+        .arg(&[giveaway.get_store_key()])
+        .query_async::<_, Vec<String>>(&mut conn)
+        .await?
+        .into_iter()
+        .filter_map(|user| match user.parse().ok().and_then(Id::new_checked) {
+            Some(user_id) => Some(user_id),
+            None => {
+                warn!(
+                    "Skipping invalid entrant {:?} of giveaway {}",
+                    user, giveaway._id
+                );
+                None
+            }
+        })
+        .collect();
+
+    let winners: Vec<Id<UserMarker>> = if users.len() > giveaway.winners {
+        users
+            .choose_multiple(&mut rand::thread_rng(), giveaway.winners)
+            .cloned()
+            .collect()
+    } else {
+        users.clone()
+    };
+
+    // dump new users into mongo
+    let users: Vec<String> = users.iter().map(|u| u.get().to_string()).collect();
+    let winners: Vec<String> = winners.iter().map(|u| u.get().to_string()).collect();
+    giveaway_coll
+        .update_one(
+            doc! {"_id": giveaway._id, "ended": {"$ne": true}},
+            doc! {
+                "$set": {"ended": true, "winner_ids": &winners},
+                "$addToSet": {"users": {"$each": users}},
+            },
+            None,
+        )
+        .await?;
+    if !winners.is_empty() {
+        let _: () = cmd("expire")
+            .arg(&[giveaway.get_store_key(), "604800".to_string()])
+            .query_async(&mut conn)
+            .await?;
+    }
+
+    giveaway_coll
+        .find_one(doc! {"_id": giveaway._id}, None)
+        .await?
+        .ok_or_else(|| Error::InvalidPayload(format!("giveaway {} was deleted", giveaway._id)))
 }
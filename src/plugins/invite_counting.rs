@@ -1,12 +1,10 @@
 use crate::core::prelude::*;
 use crate::core::Plugin;
-pub use crate::db::models::{
-    GuildInviteStorage, JoinStorage, LeaveStorage, MongoInvite, UserInviteStorage,
-};
+pub use crate::db::models::{GuildInviteStorage, JoinStorage, MongoInvite, UserInviteStorage};
 use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use twilight_model::gateway::payload::incoming::*;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct InviteCounting();
 
 #[async_trait]
@@ -18,6 +16,13 @@ impl Plugin for InviteCounting {
     fn description(&self) -> &'static str {
         "Tracks the invites for a server"
     }
+
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::INVITE_CREATE
+            | EventTypeFlags::INVITE_DELETE
+            | EventTypeFlags::MEMBER_ADD
+            | EventTypeFlags::MEMBER_REMOVE
+    }
     async fn on_event(&self, event: Event, ctx: Context) -> Result<()> {
         let http = {
             let c = ctx.clone();
@@ -68,7 +73,7 @@ impl Plugin for InviteCounting {
                             invites: Vec::<MongoInvite>::new(),
                         });
 
-                let cache: Vec<MongoInvite> = storage.invites.into_iter().map(Into::into).collect();
+                let cache: Vec<MongoInvite> = storage.invites;
 
                 if !invites.is_empty() {
                     event!(
@@ -188,8 +193,7 @@ impl Plugin for InviteCounting {
 
                 let doc = coll.find_one(doc!{ "user_id":event.user.id.get().to_string(), "guild_id":event.guild_id.get().to_string(), "doctype":"join_storage" }, None).await?;
 
-                if doc.is_some() {
-                    let doc = doc.unwrap();
+                if let Some(doc) = doc {
                     event!(Level::INFO, "Found user in join storage: {:#?}", doc);
                     if let Some(inviter_id) = doc.inviter_id {
                         let invite_coll = ctx.db.collection::<UserInviteStorage>("invites");
@@ -211,10 +215,35 @@ impl Plugin for InviteCounting {
     async fn sync_db(&self, _ctx: &Context) -> Result<()> {
         Ok(())
     }
-}
 
-impl Default for InviteCounting {
-    fn default() -> Self {
-        Self {}
+    /// Snapshots the guild's invites so the first join after enabling can
+    /// already be attributed.
+    async fn on_guild_enable(&self, guild_id: Id<GuildMarker>, ctx: &Context) -> Result<()> {
+        let invites = ctx
+            .http
+            .guild_invites(guild_id)
+            .exec()
+            .await?
+            .model()
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect::<Vec<MongoInvite>>();
+
+        event!(
+            Level::INFO,
+            "Storing {} invites for guild {}",
+            invites.len(),
+            guild_id.get()
+        );
+        ctx.db
+            .collection::<GuildInviteStorage>("invites")
+            .find_one_and_update(
+                doc! { "doctype":"invite_storage", "guild_id": guild_id.get().to_string() },
+                doc! { "$set": { "invites": bson::to_bson(&invites)? } },
+                Some(FindOneAndUpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+        Ok(())
     }
 }
@@ -5,6 +5,36 @@ use meval::eval_str;
 use regex::Regex;
 use twilight_http::request::channel::reaction::RequestReactionType;
 
+/// Per-guild settings for `MathSolving`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MathSolvingSettings {
+    /// Number suffixes, in order, each worth the previous one times ten
+    /// starting from a thousand.
+    pub suffixes: Vec<String>,
+}
+
+impl Default for MathSolvingSettings {
+    fn default() -> Self {
+        Self {
+            suffixes: vec!["k".into(), "m".into(), "b".into(), "t".into()],
+        }
+    }
+}
+
+impl PluginSettings for MathSolvingSettings {
+    const PLUGIN: &'static str = "math_solving";
+
+    fn problems(&self) -> Vec<String> {
+        // An empty suffix would be substituted between every character
+        if self.suffixes.iter().any(|suffix| suffix.trim().is_empty()) {
+            vec!["`suffixes` must not contain empty suffixes".to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MathSolving {
     pub equation_expr: Regex,
@@ -21,6 +51,10 @@ impl Plugin for MathSolving {
         "math_solving"
     }
 
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::MESSAGE_CREATE | EventTypeFlags::REACTION_ADD
+    }
+
     async fn on_event(&self, event: Event, ctx: Context) -> Result<()> {
         match event {
             Event::MessageCreate(msg) => {
@@ -32,10 +66,17 @@ impl Plugin for MathSolving {
                 if !self.equation_expr.is_match(&msg.content) {
                     return Ok(());
                 }
+                let guild_id = match msg.guild_id {
+                    Some(guild_id) => guild_id,
+                    None => return Ok(()),
+                };
+                let settings = ctx
+                    .settings
+                    .get::<MathSolvingSettings>(&ctx, guild_id)
+                    .await;
                 let mut content = msg.content.clone();
-                let options = vec!["k", "m", "b", "t"];
 
-                for (i, option) in options.iter().enumerate() {
+                for (i, option) in settings.suffixes.iter().enumerate() {
                     content = content.replace(option, &format!("* (10^{})", i + 3));
                 }
 
@@ -44,11 +85,12 @@ impl Plugin for MathSolving {
                         return Ok(());
                     }
                     let emoji = RequestReactionType::Unicode { name: "➕" };
-                    if let Ok(_) = ctx
+                    if ctx
                         .http
                         .create_reaction(msg.channel_id, msg.id, &emoji)
                         .exec()
                         .await
+                        .is_ok()
                     {
                         self.cache.insert(msg.id, result);
                     }
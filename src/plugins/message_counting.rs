@@ -3,10 +3,11 @@ use crate::core::Plugin;
 use crate::db::models::*;
 use dashmap::DashMap;
 use mongodb::options::FindOneAndUpdateOptions;
+use tokio::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct MessageCounting {
-    pub cache: DashMap<GuildId, DashMap<UserId, i64>>,
+    pub cache: DashMap<Id<GuildMarker>, DashMap<Id<UserMarker>, i64>>,
 }
 
 #[async_trait]
@@ -19,6 +20,10 @@ impl Plugin for MessageCounting {
         "Counts the number of messages sent by each user"
     }
 
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::MESSAGE_CREATE
+    }
+
     async fn on_event(&self, event: Event, _ctx: Context) -> Result<()> {
         if let Event::MessageCreate(message) = event {
             if message.author.bot {
@@ -46,6 +51,12 @@ impl Plugin for MessageCounting {
         Ok(())
     }
 
+    /// Counts are flushed on shutdown as well, so this only bounds what a
+    /// crash loses.
+    fn sync_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+
     async fn sync_db(&self, context: &Context) -> Result<()> {
         let db = {
             let ctx = context.clone();
@@ -54,12 +65,12 @@ impl Plugin for MessageCounting {
         let coll = db.collection::<MessageCountingUserStorage>("messages");
 
         for row in self.cache.iter() {
-            let guild_id = row.key().clone();
+            let guild_id = *row.key();
             let cache = row.value().clone();
 
             for g_row in cache.iter() {
-                let user_id = g_row.key().clone();
-                let count = g_row.value().clone();
+                let user_id = *g_row.key();
+                let count = *g_row.value();
                 event!(
                     Level::DEBUG,
                     "Saving message count for user {} in guild {}",
@@ -1,3 +1,12 @@
+#[cfg(any(
+    feature = "dank-memer",
+    feature = "giveaways",
+    feature = "math-solving",
+    feature = "timers"
+))]
+use crate::core::settings::configured_problems;
+use std::collections::HashMap;
+
 #[cfg(feature = "dank-memer")]
 pub mod dank_memer;
 #[cfg(feature = "date-transformer")]
@@ -8,7 +17,7 @@ pub mod giveaways;
 pub mod invite_counting;
 #[cfg(feature = "math-solving")]
 pub mod math_solving;
-#[cfg(feature = "message_counting")]
+#[cfg(feature = "message-counting")]
 pub mod message_counting;
 #[cfg(feature = "server-indexer")]
 pub mod server_indexer;
@@ -16,3 +25,27 @@ pub mod server_indexer;
 pub mod timers;
 #[cfg(feature = "utility")]
 pub mod utility;
+
+/// Problems with the settings the worker config gives each plugin, checked
+/// against the `PluginSettings` of every plugin built in.
+#[allow(unused_mut, unused_variables)]
+pub fn settings_problems(configured: &HashMap<String, serde_json::Value>) -> Vec<String> {
+    let mut problems = Vec::new();
+    #[cfg(feature = "dank-memer")]
+    problems.extend(configured_problems::<dank_memer::DankMemerSettings>(
+        configured,
+    ));
+    #[cfg(feature = "giveaways")]
+    problems.extend(configured_problems::<giveaways::GiveawaySettings>(
+        configured,
+    ));
+    #[cfg(feature = "math-solving")]
+    problems.extend(configured_problems::<math_solving::MathSolvingSettings>(
+        configured,
+    ));
+    #[cfg(feature = "timers")]
+    problems.extend(configured_problems::<timers::TimerSettings>(
+        configured,
+    ));
+    problems
+}
@@ -17,6 +17,14 @@ impl Plugin for ServerIndexer {
         "server_indexer"
     }
 
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::GUILD_CREATE | EventTypeFlags::GUILD_UPDATE | EventTypeFlags::GUILD_DELETE
+    }
+
+    fn default_enabled(&self) -> bool {
+        true
+    }
+
     async fn on_event(&self, event: Event, ctx: Context) -> Result<()> {
         match event {
             Event::GuildUpdate(e) => {
@@ -1,18 +1,49 @@
 use std::collections::HashMap;
 
 use crate::core::prelude::*;
-use crate::core::Plugin;
+use crate::core::settings::embed_text_problems;
+use crate::core::{ComponentContext, Job, Plugin, SyncScope};
 use crate::db::models::Timer;
-use chrono::{Duration as ChronoDuration, Utc};
 use futures::stream::TryStreamExt;
 use mongodb::bson::oid::ObjectId;
 use tagscript::Adapter;
-use tokio::time::sleep;
 use tracing::error;
 use tracing::info;
-use twilight_embed_builder::EmbedBuilder;
+use twilight_util::builder::embed::EmbedBuilder;
+use twilight_validate::embed::TITLE_LENGTH;
 
-#[derive(Clone, Debug)]
+/// Per-guild settings for `Timers`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimerSettings {
+    /// Title of the timer embed once it has ended.
+    pub ended_title: String,
+    /// Users pinged per message when a timer ends.
+    pub mentions_per_message: usize,
+}
+
+impl Default for TimerSettings {
+    fn default() -> Self {
+        Self {
+            ended_title: "Timer Ended".to_string(),
+            mentions_per_message: 86, // this is some random number that works
+        }
+    }
+}
+
+impl PluginSettings for TimerSettings {
+    const PLUGIN: &'static str = "timers";
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = embed_text_problems("ended_title", &self.ended_title, TITLE_LENGTH);
+        if self.mentions_per_message == 0 {
+            problems.push("`mentions_per_message` must be at least 1".to_string());
+        }
+        problems
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Timers {}
 
 #[async_trait]
@@ -25,181 +56,276 @@ impl Plugin for Timers {
         "Schedules timers"
     }
 
+    fn component_namespace(&self) -> Option<&'static str> {
+        Some("timers")
+    }
+
     async fn on_event(&self, _event: Event, _ctx: Context) -> Result<()> {
         Ok(())
     }
 
+    /// `timers:remind` toggles whether the user is pinged when the timer the
+    /// button is attached to ends.
+    async fn on_component(&self, component: ComponentContext, ctx: Context) -> Result<()> {
+        let user_id = match (component.id(), component.author_id()) {
+            ("remind", Some(user_id)) => user_id,
+            _ => return component.ack().await,
+        };
+
+        let timer = ctx
+            .db
+            .collection::<Timer>("timers")
+            .find_one(
+                doc! {"message_id": component.message_id().get().to_string()},
+                None,
+            )
+            .await?;
+        let timer = match timer {
+            Some(timer) if !timer.get_duration_remaining().is_zero() => timer,
+            _ => return component.reply_ephemeral("This timer has ended").await,
+        };
+
+        let mut conn = ctx.redis_pool.get().await?;
+        let added: bool = cmd("SADD")
+            .arg(timer.get_store_key())
+            .arg(user_id.get())
+            .query_async(&mut conn)
+            .await?;
+        if added {
+            return component
+                .reply_ephemeral("You'll be pinged when this timer ends")
+                .await;
+        }
+
+        cmd("SREM")
+            .arg(timer.get_store_key())
+            .arg(user_id.get())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        component
+            .reply_ephemeral("You won't be pinged when this timer ends")
+            .await
+    }
+
+    fn sync_scope(&self) -> SyncScope {
+        SyncScope::Cluster
+    }
+
+    /// Schedules an ending job for every timer that isn't scheduled for its
+    /// current `end` yet, which includes timers whose end was moved.
     async fn sync_db(&self, ctx: &Context) -> Result<()> {
         let timer_coll = ctx.db.collection::<Timer>("timers");
-        let timestamp = Utc::now() + ChronoDuration::seconds(60);
-        let timestamp: bson::DateTime = timestamp.into();
-
         let mut timer_cursor = timer_coll
-            .find(doc! {"active":true, "end":{"$lte":timestamp}}, None)
-            .await
-            .expect("Failed to find timers");
+            .find(
+                doc! {"active": true, "$expr": {"$ne": ["$scheduled_end", "$end"]}},
+                None,
+            )
+            .await?;
 
-        let mut results: Vec<ObjectId> = Vec::new();
         while let Some(timer) = timer_cursor.try_next().await? {
-            results.push(timer._id);
-            let ctx = ctx.clone();
-            // Editing the messages
-            tokio::spawn(async move {
-                let (http, mut conn) = {
-                    let http = ctx.http.clone();
-                    let conn = ctx.redis_pool.get().await.unwrap();
-                    (http, conn)
-                };
-                info!("Remaining: {:#?}", timer.get_duration_remaining());
-                sleep(timer.get_duration_remaining()).await;
-                info!("Ending...");
-                let embed = EmbedBuilder::new()
-                    .title("Timer Ended")
-                    .description(format!("{}", timer.get_content()))
-                    .build()
-                    .expect("could not construct embed for timer");
-
-                if let Err(why) = http
-                    .update_message(timer.get_channel_id(), timer.get_message_id())
-                    .embeds(Some(&[embed]))
-                    .expect("Could not construct update embed for timer")
-                    .components(Some(&[]))
-                    .expect("Could not construct update components for timer")
-                    .exec()
-                    .await
-                {
-                    event!(Level::ERROR, "Failed to update timer message: {}", why);
-                } else {
-                    info!("Successfully updated timer message");
-                    let mut seed_variables: HashMap<String, Adapter> = HashMap::new();
-                    seed_variables.insert("title".into(), Adapter::String(timer.title.clone()));
-                    seed_variables.insert(
-                        "host".into(),
-                        Adapter::String(format!("<@{}>", timer.get_host_id().get())),
-                    );
-                    seed_variables.insert(
-                        "channel".into(),
-                        Adapter::String(format!("<#{}>", timer.get_channel_id().get())),
-                    );
-                    seed_variables.insert(
-                        "link".into(),
-                        Adapter::String(format!(
-                            "<https://discord.com/channels/{}/{}/{}>",
-                            timer.get_guild_id().get(),
-                            timer.get_channel_id().get(),
-                            timer.get_message_id().get()
-                        )),
-                    );
-                    dbg!(&timer.end_message);
-                    dbg!(&seed_variables);
-                    let end_message = ctx
-                        .interpreter
-                        .process(timer.end_message.clone(), Some(seed_variables), Some(2000))
-                        .expect("Tagscript processing failed");
-                    http.create_message(timer.get_channel_id())
-                        .content(&end_message.body.unwrap())
-                        .expect("Could not create content for end message")
-                        .components(&[Component::ActionRow(ActionRow {
-                            components: vec![Component::Button(Button {
-                                style: ButtonStyle::Link,
-                                url: Some(format!(
-                                    "https://discord.com/channels/{}/{}/{}",
-                                    timer.get_guild_id(),
-                                    timer.get_channel_id(),
-                                    timer.get_message_id()
-                                )),
-                                label: Some("Jump".to_string()),
-                                custom_id: None,
-                                disabled: false,
-                                emoji: None,
-                            })],
-                        })])
-                        .expect("Could not construct components for end message")
-                        .allowed_mentions(Some(&AllowedMentions::builder().build()))
-                        .exec()
-                        .await
-                        .ok();
-                }
-
-                let users: Vec<String> = cmd("smembers")
-                    .arg(&[timer.get_store_key()])
-                    .query_async(&mut conn)
-                    .await
-                    .unwrap_or_else(|_| {
-                        error!("Failed to get users for timer {}", timer._id);
-                        Vec::new()
-                    });
-
-                if !users.is_empty() {
-                    let mut messages = Vec::new();
-                    for chunk in users.chunks(86) {
-                        // this is some random number that works
-                        let content = chunk
-                            .into_iter()
-                            .map(|u| format!("<@{}>", u))
-                            .collect::<Vec<String>>()
-                            .join("");
-
-                        match http
-                            .create_message(timer.get_channel_id())
-                            .content(&content)
-                            .expect("Could not create message")
-                            .exec()
-                            .await
-                        {
-                            Err(why) => {
-                                error!("Failed to send message: {}", why);
-                                break;
-                            }
-                            Ok(raw_msg) => {
-                                let msg = raw_msg.model().await.unwrap();
-                                messages.push(msg.id);
-                            }
-                        }
-                    }
-                    if !messages.is_empty() {
-                        if messages.len() == 1 {
-                            http.delete_message(timer.get_channel_id(), messages[0])
-                                .exec()
-                                .await
-                                .ok();
-                        } else {
-                            for chunk in messages.chunks(100) {
-                                if let Err(why) = http
-                                    .delete_messages(timer.get_channel_id(), chunk)
-                                    .exec()
-                                    .await
-                                {
-                                    error!("Failed to delete messages: {}", why);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    let _: () = cmd("del")
-                        .arg(&[timer.get_store_key()])
-                        .query_async(&mut conn)
-                        .await
-                        .expect("Redis cmd failed"); // clearly the command worked
-                }
-            });
-        }
-        if !results.is_empty() {
+            ctx.scheduler
+                .schedule(
+                    self.name(),
+                    &timer._id.to_hex(),
+                    timer.end.timestamp_millis(),
+                    (),
+                )
+                .await?;
+            // Only if `end` didn't move again meanwhile
             timer_coll
-                .update_many(
-                    doc! {"_id":{ "$in":results }},
-                    doc! {"$set":doc! {"active":false}},
+                .update_one(
+                    doc! {"_id": timer._id, "end": timer.end},
+                    doc! {"$set": {"scheduled_end": timer.end}},
                     None,
                 )
-                .await
-                .expect("Failed to update timers");
+                .await?;
         }
         Ok(())
     }
+
+    /// Ends the timer whose id the job carries, unless a previous run
+    /// already did or its end was moved later.
+    async fn on_job(&self, job: Job, ctx: Context) -> Result<()> {
+        let id = ObjectId::parse_str(&job.id)
+            .map_err(|_| Error::InvalidPayload(format!("timer id {}", job.id)))?;
+        let timer_coll = ctx.db.collection::<Timer>("timers");
+        let timer = match timer_coll
+            .find_one(doc! {"_id": id, "active": true}, None)
+            .await?
+        {
+            // The job for the new end takes care of it
+            Some(timer) if !timer.get_duration_remaining().is_zero() => return Ok(()),
+            Some(timer) => timer,
+            None => return Ok(()),
+        };
+
+        end_timer(&timer, &ctx).await?;
+        timer_coll
+            .update_one(doc! {"_id": id}, doc! {"$set": {"active": false}}, None)
+            .await?;
+        Ok(())
+    }
 }
 
-impl Default for Timers {
-    fn default() -> Self {
-        Timers {}
+/// Announces the end of a timer, then pings (and cleans up after) everyone
+/// who asked to be reminded.
+///
+/// Each step is recorded in the timer's `progress` once done, so a retried
+/// ending skips what the previous run already sent.
+async fn end_timer(timer: &Timer, ctx: &Context) -> Result<()> {
+    info!("Ending timer {}", timer._id);
+    let http = &ctx.http;
+    let timer_coll = ctx.db.collection::<Timer>("timers");
+    let mut conn = ctx.redis_pool.get().await?;
+
+    let settings = ctx
+        .settings
+        .get::<TimerSettings>(ctx, timer.get_guild_id())
+        .await;
+    if !timer.progress.message_updated {
+        let embed = EmbedBuilder::new()
+            .title(settings.ended_title.as_str())
+            .description(timer.get_content())
+            .validate()
+            .map_err(Error::EmbedFailed)?
+            .build();
+
+        http.update_message(timer.get_channel_id(), timer.get_message_id())
+            .embeds(Some(&[embed]))?
+            .components(Some(&[]))?
+            .exec()
+            .await?;
+        timer_coll
+            .update_one(
+                doc! {"_id": timer._id},
+                doc! {"$set": {"progress.message_updated": true}},
+                None,
+            )
+            .await?;
+        info!("Successfully updated timer message");
+    }
+
+    if !timer.progress.announced {
+        announce_end(timer, ctx).await?;
+        timer_coll
+            .update_one(
+                doc! {"_id": timer._id},
+                doc! {"$set": {"progress.announced": true}},
+                None,
+            )
+            .await?;
+    }
+
+    let mut users: Vec<String> = cmd("smembers")
+        .arg(&[timer.get_store_key()])
+        .query_async(&mut conn)
+        .await?;
+    if users.is_empty() {
+        return Ok(());
+    }
+    // So a retry chunks the users the same way and can skip the chunks that
+    // were already pinged
+    users.sort_unstable();
+
+    let mut messages: Vec<Id<MessageMarker>> = timer
+        .progress
+        .ping_messages
+        .iter()
+        .filter_map(|id| id.parse().ok().and_then(Id::new_checked))
+        .collect();
+    let chunks = users.chunks(settings.mentions_per_message.max(1));
+    for chunk in chunks.skip(timer.progress.ping_messages.len()) {
+        let content = chunk
+            .iter()
+            .map(|u| format!("<@{}>", u))
+            .collect::<Vec<String>>()
+            .join("");
+
+        // A failed chunk fails the job, so the scheduler retries it from here
+        let msg = http
+            .create_message(timer.get_channel_id())
+            .content(&content)?
+            .exec()
+            .await?
+            .model()
+            .await?;
+        timer_coll
+            .update_one(
+                doc! {"_id": timer._id},
+                doc! {"$push": {"progress.ping_messages": msg.id.get().to_string()}},
+                None,
+            )
+            .await?;
+        messages.push(msg.id);
     }
+    if messages.len() == 1 {
+        http.delete_message(timer.get_channel_id(), messages[0])
+            .exec()
+            .await
+            .ok();
+    } else {
+        for chunk in messages.chunks(100) {
+            if let Err(why) = http
+                .delete_messages(timer.get_channel_id(), chunk)
+                .exec()
+                .await
+            {
+                error!("Failed to delete messages: {}", why);
+                break;
+            }
+        }
+    }
+    let _: () = cmd("del")
+        .arg(&[timer.get_store_key()])
+        .query_async(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Sends the timer's end message, with a link back to the timer.
+async fn announce_end(timer: &Timer, ctx: &Context) -> Result<()> {
+    let mut seed_variables: HashMap<String, Adapter> = HashMap::new();
+    seed_variables.insert("title".into(), Adapter::String(timer.title.clone()));
+    seed_variables.insert(
+        "host".into(),
+        Adapter::String(format!("<@{}>", timer.get_host_id().get())),
+    );
+    seed_variables.insert(
+        "channel".into(),
+        Adapter::String(format!("<#{}>", timer.get_channel_id().get())),
+    );
+    seed_variables.insert(
+        "link".into(),
+        Adapter::String(format!(
+            "<https://discord.com/channels/{}/{}/{}>",
+            timer.get_guild_id().get(),
+            timer.get_channel_id().get(),
+            timer.get_message_id().get()
+        )),
+    );
+    let end_message =
+        ctx.interpreter
+            .process(timer.end_message.clone(), Some(seed_variables), Some(2000))?;
+    ctx.http
+        .create_message(timer.get_channel_id())
+        .content(&end_message.body.unwrap_or_default())?
+        .components(&[Component::ActionRow(ActionRow {
+            components: vec![Component::Button(Button {
+                style: ButtonStyle::Link,
+                url: Some(format!(
+                    "https://discord.com/channels/{}/{}/{}",
+                    timer.get_guild_id(),
+                    timer.get_channel_id(),
+                    timer.get_message_id()
+                )),
+                label: Some("Jump".to_string()),
+                custom_id: None,
+                disabled: false,
+                emoji: None,
+            })],
+        })])?
+        .allowed_mentions(Some(&AllowedMentions::builder().build()))
+        .exec()
+        .await?;
+    Ok(())
 }
@@ -1,9 +1,13 @@
 use crate::core::prelude::*;
-use crate::core::Plugin;
+use crate::core::{CommandContext, Plugin};
 use crate::db::models::AfkUser;
 use dashmap::DashMap;
 use futures::stream::TryStreamExt;
-use twilight_embed_builder::EmbedBuilder;
+use mongodb::options::ReplaceOptions;
+use tokio::time::Duration;
+use twilight_model::application::command::{Command, CommandType};
+use twilight_util::builder::command::{CommandBuilder, StringBuilder};
+use twilight_util::builder::embed::EmbedBuilder;
 
 #[derive(Debug, Clone)]
 pub struct Utility {
@@ -20,6 +24,60 @@ impl Plugin for Utility {
         "utility"
     }
 
+    fn events(&self) -> EventTypeFlags {
+        EventTypeFlags::MESSAGE_CREATE
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        vec![CommandBuilder::new(
+            "afk".into(),
+            "Sets or clears your AFK message".into(),
+            CommandType::ChatInput,
+        )
+        .option(StringBuilder::new("message".into(), "Shown when you're mentioned".into()).build())
+        .build()]
+    }
+
+    /// `/afk [message]` marks the user AFK, or brings them back if they
+    /// already were.
+    async fn on_command(&self, command: CommandContext, ctx: Context) -> Result<()> {
+        let user_id = match command.author_id() {
+            Some(user_id) => user_id,
+            None => return Ok(()),
+        };
+        let coll = ctx.db.collection::<AfkUser>("afk");
+        let filter = doc! {"user_id": user_id.get().to_string()};
+
+        // Other workers' caches may be behind, Mongo decides whether this is
+        // a toggle off
+        if coll
+            .find_one_and_delete(filter.clone(), None)
+            .await?
+            .is_some()
+        {
+            self.cache.remove(&user_id);
+            return command
+                .reply_ephemeral("Welcome back, your AFK has been removed")
+                .await;
+        }
+
+        let message = command.string("message").unwrap_or("AFK").to_string();
+        coll.replace_one(
+            filter,
+            AfkUser {
+                user_id: user_id.get().to_string(),
+                message: message.clone(),
+                afk_since: bson::DateTime::now(),
+            },
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+        self.cache.insert(user_id, message.clone());
+        command
+            .reply_ephemeral(format!("You're now AFK: {}", message))
+            .await
+    }
+
     async fn on_event(&self, event: Event, ctx: Context) -> Result<()> {
         if let Event::MessageCreate(message) = event {
             if message.author.bot || message.mentions.is_empty() {
@@ -29,18 +87,18 @@ impl Plugin for Utility {
             if let Some(mention) = message
                 .mentions
                 .iter()
-                .filter(|m| self.cache.get(&m.id).is_some() && m.id != message.author.id)
-                .next()
+                .find(|m| self.cache.get(&m.id).is_some() && m.id != message.author.id)
             {
                 let afk_message = &*self.cache.get(&mention.id).unwrap();
 
                 let _ = ctx
                     .http
                     .create_message(message.channel_id)
-                    .embeds(&vec![EmbedBuilder::new()
+                    .embeds(&[EmbedBuilder::new()
                         .description(afk_message)
-                        .build()
-                        .unwrap()])?
+                        .validate()
+                        .map_err(Error::EmbedFailed)?
+                        .build()])?
                     .exec()
                     .await?;
             }
@@ -48,29 +106,33 @@ impl Plugin for Utility {
         Ok(())
     }
 
+    /// `/afk` updates this worker's cache right away, the reload only picks
+    /// up changes made through other workers.
+    fn sync_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+
     async fn sync_db(&self, ctx: &Context) -> Result<()> {
         let coll = ctx.db.collection::<AfkUser>("afk");
-        let mut afk_cursor = coll
-            .find(doc! {}, None)
-            .await
-            .expect("Failed to find afk users");
+        let mut afk_cursor = coll.find(doc! {}, None).await?;
 
         let mut users = Vec::new();
         while let Some(afk_user) = afk_cursor.try_next().await? {
             let id = afk_user.get_user_id();
-            users.push(id.clone());
+            users.push(id);
 
             self.cache.insert(id, afk_user.message.clone());
         }
 
-        for item in self.cache.iter_mut() {
-            if !users.contains(&item.key()) {
-                self.cache.remove(&item.key());
-            }
-        }
+        self.cache.retain(|id, _| users.contains(id));
 
         Ok(())
     }
+
+    /// Fills the AFK cache straight away instead of waiting for the first sync.
+    async fn on_load(&self, ctx: &Context) -> Result<()> {
+        self.sync_db(ctx).await
+    }
 }
 
 impl Default for Utility {
@@ -1,21 +1,34 @@
 use crate::context::Context;
-use crate::core::EventHandler;
-use crate::model::{PluginConfig, WorkerConfig};
-use lapin::options::{BasicConsumeOptions, QueueDeclareOptions};
-use lapin::{types::FieldTable, Connection, ConnectionProperties};
+use crate::core::backoff::{self, Backoff};
+#[cfg(feature = "mongo")]
+use crate::core::metrics::MongoPoolMetrics;
+use crate::core::{
+    http, EventHandler, Health, Leases, Metrics, RabbitConnection, RedisCache, RedisRatelimiter,
+    Scheduler, SettingsStore, Shutdown, SyncScope,
+};
+use crate::model::{active_plugins_key, PluginConfig, WorkerConfig, PLUGIN_UPDATES_CHANNEL};
+use futures::stream::StreamExt;
+use lapin::options::{BasicConsumeOptions, BasicQosOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{Channel, Consumer};
+use rand::Rng;
 use std::collections::HashMap;
 use std::sync::Arc;
 #[cfg(feature = "tagscript")]
 use tagscript::{block, Interpreter};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
-use tokio::time::{sleep, Duration};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout, Duration, Instant};
 use tracing::{event, Level};
-use twilight_cache_inmemory::InMemoryCache;
 use twilight_http::Client as HttpClient;
 // Databases
 use crate::core::prelude::*;
+#[cfg(feature = "mongo")]
 use crate::db::{MongoClient, MongoClientOptions};
+use deadpool_redis::redis::Client as RedisClient;
 use deadpool_redis::Runtime;
+#[cfg(feature = "mongo")]
 use mongodb::options::Compressor;
 
 #[non_exhaustive]
@@ -23,87 +36,82 @@ pub struct Worker {
     pub handler: EventHandler,
     pub config: WorkerConfig,
     pub ctx: Context,
+    /// Dedicated (non-pooled) client for pub/sub subscriptions
+    pub redis_client: RedisClient,
 }
 
 impl Worker {
     pub async fn new(config: WorkerConfig, plugins: Arc<Vec<Arc<Box<dyn Plugin>>>>) -> Self {
-        let http = Arc::new(HttpClient::new(config.discord_token.clone()));
-        let cache = Arc::new(InMemoryCache::new());
+        let metrics = Arc::new(Metrics::new());
 
-        // Setting up MongoDB Connection
-        let mut mongo_options = MongoClientOptions::parse(&config.mongo_uri)
-            .await
-            .expect("Failed to parse mongo uri into connection options");
-
-        mongo_options.compressors = Some(vec![Compressor::Zstd {
-            level: Default::default(),
-        }]);
-
-        let mongo_client = Arc::new(
-            MongoClient::with_options(mongo_options).expect("Failed to create MongoClient"),
-        );
-        let mongo_db = mongo_client.database(&config.mongo_db);
+        #[cfg(feature = "mongo")]
+        let (mongo_client, mongo_db) = Worker::connect_mongo(&config, &metrics).await;
         // Setting up Redis connection
         let redis_pool = config
             .redis
             .create_pool(Some(Runtime::Tokio1))
             .expect("Failed to create Redis pool");
         let redis_pool = Arc::new(redis_pool);
+        let cache = Arc::new(RedisCache::new(
+            redis_pool.clone(),
+            Duration::from_secs(config.cache_message_ttl),
+            Duration::from_secs(config.cache_entity_ttl),
+        ));
+        // Buckets live in Redis so every worker on this token shares its limits
+        let http = Arc::new(
+            HttpClient::builder()
+                .token(config.discord_token.clone())
+                .ratelimiter(Some(Box::new(RedisRatelimiter::new(redis_pool.clone()))))
+                .build(),
+        );
+        let redis_client = match (&config.redis.url, &config.redis.connection) {
+            (Some(url), _) => RedisClient::open(url.as_str()),
+            (None, Some(connection)) => RedisClient::open(connection.clone()),
+            (None, None) => RedisClient::open("redis://127.0.0.1/"),
+        }
+        .expect("Failed to create Redis client");
 
-        let app_info = http
-            .current_user_application()
-            .exec()
-            .await
-            .expect("Unable to retrieve application info.")
-            .model()
-            .await
-            .expect("Unable to retrieve application info");
-        let user = http
-            .current_user()
-            .exec()
-            .await
-            .expect("Unable to retrieve current user.")
-            .model()
-            .await
-            .expect("Unable to retrieve current user");
+        // Nothing works without these, so wait for them to come up instead of
+        // crash looping while a dependency restarts
+        let app_info = backoff::retry("retrieve application info", || async {
+            Ok(http
+                .current_user_application()
+                .exec()
+                .await?
+                .model()
+                .await?)
+        })
+        .await
+        .expect("Unable to retrieve application info");
+        let user = backoff::retry("retrieve current user", || async {
+            Ok(http.current_user().exec().await?.model().await?)
+        })
+        .await
+        .expect("Unable to retrieve current user");
 
-        let owner = app_info.owner;
         let mut owners = HashMap::new();
-        owners.insert(owner.id, Arc::new(owner));
+        if let Some(owner) = app_info.owner {
+            owners.insert(owner.id, Arc::new(owner));
+        }
 
-        let rabbit_conn = Connection::connect(&config.rabbit_uri, ConnectionProperties::default())
+        backoff::retry("connect to Redis", || async {
+            let mut conn = redis_pool.get().await?;
+            cmd("PING").query_async::<_, String>(&mut conn).await?;
+            Ok(())
+        })
+        .await
+        .expect("Failed to connect to Redis");
+
+        let rabbit_conn = RabbitConnection::connect(&config.rabbit_uri)
             .await
             .expect("Failed to connect to RabbitMQ");
         let rabbit_conn = Arc::new(rabbit_conn);
 
-        let channel = rabbit_conn
-            .create_channel()
-            .await
-            .expect("Could not create RabbitMQ channel");
-
-        let channel2 = rabbit_conn
-            .create_channel()
-            .await
-            .expect("Could not create RabbitMQ channel");
-
-        channel2
-            .queue_declare(
-                &config.rabbit_queue,
-                QueueDeclareOptions::default(),
-                FieldTable::default(),
-            )
-            .await
-            .expect("Could not initialize queue");
-
-        let consumer = channel
-            .basic_consume(
-                &config.rabbit_queue,
-                "gateway-worker",
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
-            )
-            .await
-            .expect("Could not create RabbitMQ consumer");
+        let (channel, consumer) = backoff::retry("create RabbitMQ consumer", || {
+            Worker::create_consumer(&rabbit_conn, &config)
+        })
+        .await
+        .expect("Could not create RabbitMQ consumer");
         // let (cluster, events) = Cluster::builder(config.discord_token.clone(), intents)
         //     .shard_scheme(ShardScheme::Auto)
         //     .http_client(http.clone())
@@ -111,7 +119,8 @@ impl Worker {
         //     .await
         //     .unwrap_or_else(|err| panic!("Unabled to setup cluster: {}", err));
 
-        let plugin_config = PluginConfig::new(plugins);
+        let plugin_config =
+            PluginConfig::new(plugins, Duration::from_secs(config.plugin_cache_ttl));
         let plugin_config = Arc::new(RwLock::new(plugin_config));
 
         #[cfg(feature = "tagscript")]
@@ -132,6 +141,13 @@ impl Worker {
             Box::new(block::StopBlock {}),
             Box::new(block::SubstringBlock {}),
         ]));
+        let scheduler = Arc::new(Scheduler::new(
+            redis_pool.clone(),
+            Duration::from_millis(config.scheduler_poll_interval),
+            Duration::from_secs(config.plugin_timeout),
+            config.job_max_attempts,
+        ));
+        let leases = Arc::new(Leases::new(redis_pool.clone()));
         let ctx = Context {
             cache,
             http,
@@ -139,19 +155,31 @@ impl Worker {
             owners,
             #[cfg(feature = "tagscript")]
             interpreter,
+            #[cfg(feature = "mongo")]
             mongo_client,
+            #[cfg(feature = "mongo")]
             db: mongo_db,
             redis_pool,
             rabbit_conn: rabbit_conn.clone(),
             plugin_config: plugin_config.clone(),
+            settings: Arc::new(SettingsStore::new(
+                Duration::from_secs(config.plugin_cache_ttl),
+                config.plugin_settings(),
+            )),
+            scheduler,
+            leases,
+            shutdown: Shutdown::new(),
+            metrics,
+            health: Arc::new(Health::new()),
         };
 
-        let handler = EventHandler::new(ctx.clone(), consumer);
+        let handler = EventHandler::new(ctx.clone(), consumer, channel, config.clone());
 
         Self {
             ctx,
             config,
             handler,
+            redis_client,
         }
     }
 
@@ -162,26 +190,654 @@ impl Worker {
         //     cluster_spawn.up().await;
         // });
 
+        let shutdown = self.ctx.shutdown.clone();
+        let _signal_handle = tokio::spawn(async move {
+            if let Err(why) = Worker::wait_for_signal().await {
+                event!(Level::ERROR, "Failed to listen for signals: {:?}", why);
+                return;
+            }
+            event!(Level::INFO, "Received shutdown signal");
+            shutdown.trigger();
+        });
+
         let ctx = self.ctx.clone();
-        let _db_sync_handle = tokio::spawn(async move {
-            Worker::db_sync_handler(ctx).await;
+        let http_addr = self.config.http_addr;
+        let _http_handle = tokio::spawn(async move {
+            if let Err(why) = http::serve(ctx, http_addr).await {
+                event!(Level::ERROR, "HTTP server failed: {:?}", why);
+            }
         });
+        let ctx = self.ctx.clone();
+        let config = self.config.clone();
+        let db_sync_handle = tokio::spawn(async move {
+            Worker::db_sync_handler(ctx, config).await;
+        });
+        let ctx = self.ctx.clone();
+        let _scheduler_handle = tokio::spawn(async move {
+            Scheduler::run(ctx).await;
+        });
+        let ctx = self.ctx.clone();
+        let redis_client = self.redis_client.clone();
+        let _plugin_updates_handle = tokio::spawn(async move {
+            Worker::plugin_updates_handler(ctx, redis_client).await;
+        });
+        let ctx = self.ctx.clone();
+        let queue = self.config.rabbit_queue.clone();
+        let _queue_stats_handle = tokio::spawn(async move {
+            Worker::queue_stats_handler(ctx, queue).await;
+        });
+        for plugin in self.ctx.plugin_config.read().await.plugins.iter() {
+            if let Err(why) = plugin.on_load(&self.ctx).await {
+                event!(Level::ERROR, "Failed to load {}: {:?}", plugin.name(), why);
+            }
+        }
+
+        if let Err(why) = self.register_commands().await {
+            event!(Level::ERROR, "Failed to register commands: {:?}", why);
+        }
+
         event!(Level::DEBUG, "Starting Event Handler");
         self.start_handler().await;
+
+        // The consumer can also end without a signal (e.g. the broker
+        // cancelled it), either way we're going down now
+        self.ctx.shutdown.trigger();
+        self.shutdown(db_sync_handle).await;
+    }
+
+    /// Registers every plugin's application commands globally, leaving
+    /// commands this worker doesn't know about untouched.
+    async fn register_commands(&self) -> Result<()> {
+        let plugin_config = self.ctx.plugin_config.read().await;
+        if plugin_config.commands.is_empty() {
+            return Ok(());
+        }
+
+        let interaction = self
+            .ctx
+            .http
+            .interaction(Id::new(self.config.application_id));
+        let mut commands: Vec<_> = interaction
+            .global_commands()
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .filter(|command| !plugin_config.commands.contains_key(&command.name))
+            .collect();
+        commands.extend(
+            plugin_config
+                .commands
+                .values()
+                .map(|(command, _)| command.clone()),
+        );
+
+        interaction.set_global_commands(&commands).exec().await?;
+        event!(
+            Level::INFO,
+            "Registered {} commands",
+            plugin_config.commands.len()
+        );
+        Ok(())
     }
 
     async fn start_handler(&mut self) {
-        self.handler.start().await
+        while let Err(why) = self.handler.start().await {
+            event!(Level::ERROR, "Event consumer failed: {:?}", why);
+            if !self.reconnect_consumer().await {
+                break;
+            }
+        }
     }
 
-    async fn db_sync_handler(ctx: Context) {
+    /// Sets up the Mongo client, waiting for the server to answer a ping.
+    #[cfg(feature = "mongo")]
+    async fn connect_mongo(
+        config: &WorkerConfig,
+        metrics: &Metrics,
+    ) -> (Arc<MongoClient>, mongodb::Database) {
+        let mut mongo_options = MongoClientOptions::parse(&config.mongo_uri)
+            .await
+            .expect("Failed to parse mongo uri into connection options");
+
+        mongo_options.compressors = Some(vec![Compressor::Zstd {
+            level: Default::default(),
+        }]);
+        mongo_options.cmap_event_handler =
+            Some(Arc::new(MongoPoolMetrics(metrics.mongo_pool.clone())));
+
+        let mongo_client = Arc::new(
+            MongoClient::with_options(mongo_options).expect("Failed to create MongoClient"),
+        );
+        let mongo_db = mongo_client.database(&config.mongo_db);
+
+        backoff::retry("connect to MongoDB", || async {
+            mongo_db.run_command(doc! {"ping": 1}, None).await?;
+            Ok(())
+        })
+        .await
+        .expect("Failed to connect to MongoDB");
+        (mongo_client, mongo_db)
+    }
+
+    /// Declares our queues, then opens a channel with our prefetch window and
+    /// starts consuming `rabbit_queue` on it.
+    ///
+    /// Reconnects to RabbitMQ first if the connection was lost.
+    async fn create_consumer(
+        rabbit_conn: &RabbitConnection,
+        config: &WorkerConfig,
+    ) -> Result<(Channel, Consumer)> {
+        rabbit_conn.reconnect().await?;
+        let channel = rabbit_conn.get().create_channel().await?;
+        // The broker may have come back without them
+        channel
+            .queue_declare(
+                &config.rabbit_queue,
+                config.rabbit_queue_options.declare_options(),
+                FieldTable::default(),
+            )
+            .await?;
+        if config.ack_after_processing {
+            let mut arguments = FieldTable::default();
+            arguments.insert(
+                "x-message-ttl".into(),
+                AMQPValue::LongLongInt((config.retry_delay * 1000) as i64),
+            );
+            arguments.insert(
+                "x-dead-letter-exchange".into(),
+                AMQPValue::LongString("".into()),
+            );
+            arguments.insert(
+                "x-dead-letter-routing-key".into(),
+                AMQPValue::LongString(config.rabbit_queue.as_str().into()),
+            );
+            channel
+                .queue_declare(
+                    &config.retry_queue(),
+                    config.rabbit_queue_options.declare_options(),
+                    arguments,
+                )
+                .await?;
+        }
+        if let Some(dead_letter_queue) = &config.rabbit_dead_letter_queue {
+            channel
+                .queue_declare(
+                    dead_letter_queue,
+                    config.rabbit_queue_options.declare_options(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+        channel
+            .basic_qos(config.rabbit_prefetch, BasicQosOptions::default())
+            .await?;
+        let consumer = channel
+            .basic_consume(
+                &config.rabbit_queue,
+                &config.consumer_tag,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        Ok((channel, consumer))
+    }
+
+    /// Replaces the handler's consumer, retrying until it works or shutdown
+    /// is triggered. Returns whether the handler should be restarted.
+    async fn reconnect_consumer(&mut self) -> bool {
+        let mut shutdown = self.ctx.shutdown.clone();
+        let mut backoff = Backoff::default();
+        loop {
+            if shutdown.is_shutdown() {
+                return false;
+            }
+            match Worker::create_consumer(&self.ctx.rabbit_conn, &self.config).await {
+                Ok((channel, consumer)) => {
+                    event!(Level::INFO, "Reconnected event consumer");
+                    self.handler.set_consumer(channel, consumer);
+                    return true;
+                }
+                Err(why) => event!(Level::ERROR, "Failed to recreate consumer: {:?}", why),
+            }
+            tokio::select! {
+                _ = sleep(backoff.next_delay()) => {}
+                _ = shutdown.recv() => return false,
+            }
+        }
+    }
+
+    async fn wait_for_signal() -> Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => {}
+            res = tokio::signal::ctrl_c() => res?,
+        }
+        Ok(())
+    }
+
+    /// Drains in-flight work, flushes every plugin one last time and closes
+    /// our connections.
+    async fn shutdown(&mut self, mut db_sync_handle: JoinHandle<()>) {
+        let deadline = Duration::from_secs(self.config.shutdown_timeout);
+        let started = Instant::now();
+
+        // Let a sync that's already running finish first, so anything it
+        // spawns is drained below and it can't race the final sync
+        match timeout(deadline, &mut db_sync_handle).await {
+            Ok(Ok(())) => {}
+            Ok(Err(why)) => event!(Level::ERROR, "db sync task failed: {:?}", why),
+            Err(_) => {
+                event!(
+                    Level::WARN,
+                    "Abandoning db sync that did not finish in time"
+                );
+                db_sync_handle.abort();
+            }
+        }
+
+        let remaining = deadline.saturating_sub(started.elapsed());
+        event!(
+            Level::INFO,
+            "Waiting up to {:?} for {} tasks to finish",
+            remaining,
+            self.ctx.shutdown.pending()
+        );
+        if timeout(remaining, self.ctx.shutdown.wait()).await.is_err() {
+            event!(
+                Level::WARN,
+                "Abandoning {} tasks that did not finish in time",
+                self.ctx.shutdown.pending()
+            );
+        }
+
+        for plugin in self.ctx.plugin_config.read().await.plugins.iter() {
+            // Cluster-wide syncs are left to the workers still running
+            let result = match plugin.sync_scope() {
+                SyncScope::Worker => Worker::sync_plugin(plugin, &self.ctx).await,
+                SyncScope::Cluster => self.ctx.leases.release(&sync_lease(plugin)).await,
+            };
+            if let Err(why) = result {
+                event!(
+                    Level::ERROR,
+                    "Failed final db sync for {}: {:?}",
+                    plugin.name(),
+                    why
+                );
+            };
+            if let Err(why) = plugin.on_unload(&self.ctx).await {
+                event!(
+                    Level::ERROR,
+                    "Failed to unload {}: {:?}",
+                    plugin.name(),
+                    why
+                );
+            }
+        }
+
+        if let Err(why) = self
+            .ctx
+            .rabbit_conn
+            .get()
+            .close(200, "worker shutting down")
+            .await
+        {
+            event!(
+                Level::ERROR,
+                "Failed to close RabbitMQ connection: {:?}",
+                why
+            );
+        }
+        self.ctx.redis_pool.close();
+        event!(Level::INFO, "Shutdown complete");
+    }
+
+    /// Syncs every plugin on its own timer until shutdown, so a slow sync
+    /// only holds up the next run of that same plugin.
+    ///
+    /// Each loop runs on its own task so a panicking plugin only stops its
+    /// own syncs. Dropping this future, as an abandoned shutdown does, aborts
+    /// all of them.
+    async fn db_sync_handler(ctx: Context, config: WorkerConfig) {
+        let plugins = ctx.plugin_config.read().await.plugins.clone();
+        let default_interval = Duration::from_secs(config.sync_interval);
+        let mut loops = JoinSet::new();
+        for plugin in plugins.iter() {
+            let interval = config
+                .plugin_sync_interval(plugin.name())
+                .or_else(|| plugin.sync_interval())
+                .unwrap_or(default_interval);
+            let (plugin, ctx) = (plugin.clone(), ctx.clone());
+            loops.spawn(async move { Worker::plugin_sync_loop(&plugin, &ctx, interval).await });
+        }
+        loops.spawn(async move { Worker::reconcile_guild_hooks(&ctx, default_interval).await });
+        while let Some(result) = loops.join_next().await {
+            if let Err(why) = result {
+                event!(Level::ERROR, "db sync loop failed: {:?}", why);
+            }
+        }
+    }
+
+    /// Runs the guild hooks for every guild in the local plugin cache each
+    /// `interval`, catching changes published while no worker was listening.
+    async fn reconcile_guild_hooks(ctx: &Context, interval: Duration) {
+        let mut shutdown = ctx.shutdown.clone();
+        loop {
+            tokio::select! {
+                _ = sleep(jittered(interval)) => {}
+                _ = shutdown.recv() => return,
+            }
+            let guilds = ctx.plugin_config.read().await.cached_guilds();
+            for guild_id in guilds {
+                // A pass over every cached guild can take a while, don't make
+                // shutdown wait for the rest of it
+                if shutdown.is_shutdown() {
+                    return;
+                }
+                Worker::run_guild_hooks(ctx.clone(), guild_id).await;
+            }
+        }
+    }
+
+    /// Runs a plugin's `sync_db` every `interval` until shutdown.
+    ///
+    /// The wait starts once the previous sync finished, so a sync that
+    /// overruns its interval delays the next one rather than overlapping it.
+    /// Each wait is jittered so workers started together don't all hit the
+    /// database at the same moment.
+    async fn plugin_sync_loop(plugin: &Arc<Box<dyn Plugin>>, ctx: &Context, interval: Duration) {
+        let mut shutdown = ctx.shutdown.clone();
+        // Outlives a couple of missed syncs before another worker takes over
+        let lease_ttl = interval * 3;
+        loop {
+            tokio::select! {
+                _ = sleep(jittered(interval)) => {}
+                _ = shutdown.recv() => return,
+            }
+            if !Worker::holds_sync(plugin, ctx, lease_ttl).await {
+                continue;
+            }
+            let result = match plugin.sync_scope() {
+                SyncScope::Worker => Worker::sync_plugin(plugin, ctx).await,
+                SyncScope::Cluster => Worker::renewing_sync(plugin, ctx, lease_ttl).await,
+            };
+            if let Err(why) = result {
+                event!(
+                    Level::ERROR,
+                    "Failed to sync db for {}: {:?}",
+                    plugin.name(),
+                    why
+                );
+            };
+        }
+    }
+
+    /// Whether this worker should run a plugin's `sync_db`: always for
+    /// per-worker syncs, only while holding the plugin's sync lease for
+    /// cluster-wide ones.
+    async fn holds_sync(plugin: &Arc<Box<dyn Plugin>>, ctx: &Context, ttl: Duration) -> bool {
+        if plugin.sync_scope() == SyncScope::Worker {
+            return true;
+        }
+        match ctx.leases.acquire(&sync_lease(plugin), ttl).await {
+            Ok(held) => held,
+            Err(why) => {
+                event!(
+                    Level::ERROR,
+                    "Failed to acquire sync lease for {}: {:?}",
+                    plugin.name(),
+                    why
+                );
+                false
+            }
+        }
+    }
+
+    /// Runs a cluster-wide sync while renewing its lease, so a sync that
+    /// outlasts the TTL isn't joined by another worker taking over. The sync
+    /// is dropped as soon as someone else turns out to hold the lease.
+    async fn renewing_sync(
+        plugin: &Arc<Box<dyn Plugin>>,
+        ctx: &Context,
+        ttl: Duration,
+    ) -> Result<()> {
+        let lease = sync_lease(plugin);
+        let renew = async {
+            loop {
+                sleep(ttl / 3).await;
+                match ctx.leases.acquire(&lease, ttl).await {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    // The lease outlives a couple of failed renewals
+                    Err(why) => event!(
+                        Level::WARN,
+                        "Failed to renew sync lease for {}: {:?}",
+                        plugin.name(),
+                        why
+                    ),
+                }
+            }
+        };
+        tokio::select! {
+            result = Worker::sync_plugin(plugin, ctx) => result,
+            _ = renew => Err(Error::LeaseLost(lease)),
+        }
+    }
+
+    /// Runs a plugin's `sync_db`, recording how long it took, whether it
+    /// failed and when it last succeeded.
+    async fn sync_plugin(plugin: &Arc<Box<dyn Plugin>>, ctx: &Context) -> Result<()> {
+        let timer = ctx
+            .metrics
+            .plugin_sync_duration
+            .with_label_values(&[plugin.name()])
+            .start_timer();
+        let result = plugin.sync_db(ctx).await;
+        timer.observe_duration();
+        match &result {
+            Ok(()) => ctx.health.synced(plugin.name()),
+            Err(why) => ctx
+                .metrics
+                .plugin_sync_errors
+                .with_label_values(&[plugin.name(), why.variant()])
+                .inc(),
+        }
+        result
+    }
+
+    /// Periodically records how far behind the queue is and how much work is
+    /// in flight, so a backed up pod shows up before it falls over.
+    async fn queue_stats_handler(ctx: Context, queue: String) {
+        let mut channel: Option<Channel> = None;
         loop {
             sleep(Duration::from_secs(15)).await;
-            for plugin in ctx.plugin_config.read().await.plugins.iter() {
-                if let Err(why) = plugin.sync_db(&ctx).await {
-                    event!(Level::ERROR, "Failed to sync db: {:?}", why);
+            // The channel dies with the connection, open a new one once the
+            // consumer has reconnected
+            if !channel.as_ref().is_some_and(|c| c.status().connected()) {
+                channel = match ctx.rabbit_conn.get().create_channel().await {
+                    Ok(channel) => Some(channel),
+                    Err(why) => {
+                        event!(Level::ERROR, "Could not create stats channel: {:?}", why);
+                        None
+                    }
                 };
             }
+            if let Some(channel) = &channel {
+                match channel
+                    .queue_declare(
+                        &queue,
+                        QueueDeclareOptions {
+                            passive: true,
+                            ..Default::default()
+                        },
+                        FieldTable::default(),
+                    )
+                    .await
+                {
+                    Ok(q) => ctx.metrics.queue_depth.set(q.message_count().into()),
+                    Err(why) => event!(Level::ERROR, "Failed to poll queue depth: {:?}", why),
+                }
+            }
+            event!(
+                Level::INFO,
+                queue_depth = ctx.metrics.queue_depth.get(),
+                in_flight = ctx.metrics.events_in_flight.get(),
+                malformed = ctx
+                    .metrics
+                    .deliveries
+                    .with_label_values(&["UNKNOWN", "malformed"])
+                    .get(),
+                "Event handler stats"
+            );
         }
     }
+
+    /// Runs `on_guild_enable` / `on_guild_disable` for plugins a guild has
+    /// just toggled. A failed hook gives up its claim so the next update for
+    /// the guild retries it.
+    async fn run_guild_hooks(ctx: Context, guild_id: Id<GuildMarker>) {
+        let changes = match ctx
+            .plugin_config
+            .read()
+            .await
+            .claim_changes(&ctx, guild_id)
+            .await
+        {
+            Ok(changes) => changes,
+            Err(why) => {
+                event!(
+                    Level::ERROR,
+                    "Failed to check plugin changes for guild {}: {:?}",
+                    guild_id,
+                    why
+                );
+                return;
+            }
+        };
+
+        for plugin in changes.enabled {
+            event!(
+                Level::INFO,
+                "Enabling {} in guild {}",
+                plugin.name(),
+                guild_id
+            );
+            if let Err(why) = plugin.on_guild_enable(guild_id, &ctx).await {
+                event!(
+                    Level::ERROR,
+                    "Failed to enable {} in guild {}: {:?}",
+                    plugin.name(),
+                    guild_id,
+                    why
+                );
+                Worker::release_claim(&ctx, guild_id, "SREM", plugin.name()).await;
+            }
+        }
+        for plugin in changes.disabled {
+            event!(
+                Level::INFO,
+                "Disabling {} in guild {}",
+                plugin.name(),
+                guild_id
+            );
+            if let Err(why) = plugin.on_guild_disable(guild_id, &ctx).await {
+                event!(
+                    Level::ERROR,
+                    "Failed to disable {} in guild {}: {:?}",
+                    plugin.name(),
+                    guild_id,
+                    why
+                );
+                Worker::release_claim(&ctx, guild_id, "SADD", plugin.name()).await;
+            }
+        }
+    }
+
+    /// Undoes a `claim_changes` claim with `command` (`SADD` or `SREM`).
+    async fn release_claim(ctx: &Context, guild_id: Id<GuildMarker>, command: &str, plugin: &str) {
+        let result: Result<()> = async {
+            let mut conn = ctx.redis_pool.get().await?;
+            cmd(command)
+                .arg(active_plugins_key(guild_id))
+                .arg(plugin)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            Ok(())
+        }
+        .await;
+        if let Err(why) = result {
+            event!(
+                Level::ERROR,
+                "Failed to release {} claim for guild {}: {:?}",
+                plugin,
+                guild_id,
+                why
+            );
+        }
+    }
+
+    /// Keeps the local guild plugin cache in line with dashboard changes,
+    /// resubscribing whenever the pub/sub connection drops.
+    async fn plugin_updates_handler(ctx: Context, client: RedisClient) {
+        loop {
+            if let Err(why) = Worker::listen_plugin_updates(&ctx, &client).await {
+                event!(
+                    Level::ERROR,
+                    "Plugin updates subscription failed: {:?}",
+                    why
+                );
+            }
+            // Anything published while we were disconnected was missed
+            ctx.plugin_config.read().await.invalidate(None);
+            ctx.settings.invalidate(None);
+            sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    async fn listen_plugin_updates(ctx: &Context, client: &RedisClient) -> Result<()> {
+        let mut pubsub = client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(PLUGIN_UPDATES_CHANNEL).await?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = msg.get_payload()?;
+            if payload == "*" {
+                event!(Level::DEBUG, "Invalidating plugin cache for every guild");
+                ctx.plugin_config.read().await.invalidate(None);
+                ctx.settings.invalidate(None);
+                continue;
+            }
+            let guild_id = match payload.parse().ok().and_then(Id::new_checked) {
+                Some(guild_id) => guild_id,
+                None => {
+                    event!(
+                        Level::WARN,
+                        "Ignoring malformed plugin update {:?}",
+                        payload
+                    );
+                    continue;
+                }
+            };
+
+            event!(Level::DEBUG, "Invalidating plugin cache for {}", guild_id);
+            ctx.plugin_config.read().await.invalidate(Some(guild_id));
+            ctx.settings.invalidate(Some(guild_id));
+            let ctx = ctx.clone();
+            ctx.shutdown
+                .clone()
+                .spawn(async move { Worker::run_guild_hooks(ctx, guild_id).await });
+        }
+        Ok(())
+    }
+}
+
+/// Lease held by the worker running a cluster-wide plugin's `sync_db`.
+fn sync_lease(plugin: &Arc<Box<dyn Plugin>>) -> String {
+    format!("sync:{}", plugin.name())
+}
+
+/// `interval` give or take 10%.
+fn jittered(interval: Duration) -> Duration {
+    interval.mul_f64(rand::thread_rng().gen_range(0.9..1.1))
 }